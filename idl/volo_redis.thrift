@@ -14,10 +14,110 @@ enum RedisCommand {
     ClusterAddSlots,
     // INTERNALS:
     Fetch,
-    SyncGot,
+    ReplStream,
     WATCH,
     MULTI,
     EXEC,
+    // STRINGS:
+    SetNx,
+    SetEx,
+    PSetEx,
+    MGet,
+    MSet,
+    MSetNx,
+    Incr,
+    Decr,
+    IncrBy,
+    DecrBy,
+    IncrByFloat,
+    Append,
+    StrLen,
+    GetRange,
+    SetRange,
+    GetSet,
+    GetDel,
+    GetEx,
+    // KEYS:
+    Exists,
+    Type,
+    DbSize,
+    RandomKey,
+    Keys,
+    Scan,
+    Expire,
+    PExpire,
+    ExpireAt,
+    PExpireAt,
+    Ttl,
+    PTtl,
+    ExpireTime,
+    PExpireTime,
+    Persist,
+    Rename,
+    RenameNx,
+    Copy,
+    Move,
+    // DATABASES:
+    Select,
+    SwapDb,
+    FlushDb,
+    FlushAll,
+    // PERSISTENCE:
+    BgRewriteAof,
+    Save,
+    BgSave,
+    LastSave,
+    // LISTS:
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    LRange,
+    LLen,
+    LIndex,
+    LSet,
+    LRem,
+    LTrim,
+    // HASHES:
+    HSet,
+    HGet,
+    HmGet,
+    HDel,
+    HExists,
+    HLen,
+    HKeys,
+    HVals,
+    HGetAll,
+    HIncrBy,
+    // SETS:
+    SAdd,
+    SRem,
+    SIsMember,
+    SmIsMember,
+    SCard,
+    SMembers,
+    SPop,
+    SRandMember,
+    SInter,
+    SUnion,
+    SDiff,
+    SInterStore,
+    SUnionStore,
+    SDiffStore,
+    // SORTED SETS:
+    ZAdd,
+    ZRem,
+    ZScore,
+    ZIncrBy,
+    ZCard,
+    ZRank,
+    ZRevRank,
+    ZRange,
+    ZRangeByScore,
+    ZCount,
+    ZPopMin,
+    ZPopMax,
+    ZRemRangeByScore,
 }
 
 struct GetItemRequest {
@@ -25,11 +125,14 @@ struct GetItemRequest {
     2: optional list<string> args,
     3: optional string client_id,
     4: optional string transaction_id,
+    // Database to run the command against, 0 if not set
+    5: optional i32 db,
 }
 
 struct GetItemResponse {
     1: required bool ok,
     2: optional string data,
+    3: optional list<GetItemResponse> items,
 }
 
 struct MultiGetItemResponse {
@@ -37,7 +140,28 @@ struct MultiGetItemResponse {
     1: optional list<GetItemResponse> data,
 }
 
+// Closes the data of a full sync, which is checked against it before it is loaded
+struct SyncEnd {
+    1: required i64 length,
+    // CRC-32 (IEEE) of the data
+    2: required i64 checksum,
+    // Position in the replication stream of the master the data is at
+    3: required string repl_id,
+    4: required i64 repl_offset,
+}
+
+// A part of the data of a full sync, the serialized databases, sent in order
+struct SyncChunkRequest {
+    1: required string transfer_id,
+    // Position of the chunk in the data
+    2: required i64 offset,
+    3: required binary data,
+    // Only on the last chunk
+    4: optional SyncEnd end,
+}
+
 service ItemService {
     GetItemResponse GetItem(1: GetItemRequest req),
     MultiGetItemResponse Exec(1: GetItemRequest req),
+    GetItemResponse SyncChunk(1: SyncChunkRequest req),
 }
\ No newline at end of file
@@ -0,0 +1,644 @@
+//! AOF records, RESP-encoded as in the Redis AOF: `*<argc>\r\n` then `$<len>\r\n<arg>\r\n`
+//! for each argument, so that keys and values may hold any character.
+//! Files written before this format hold one space-separated line per write, `SET key value <deadline>`
+//! or `DEL key 0 0`, and are still read as the records of today.
+
+use crate::cmdargs::AppendFsync;
+use crate::redis::Record;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Longest argument accepted, as the strings are limited to 512MB
+const MAX_ARG_LEN: usize = 512 * 1024 * 1024;
+
+pub fn encode(record: &[String]) -> String {
+    let mut out = format!("*{}\r\n", record.len());
+    for arg in record {
+        let _ = write!(out, "${}\r\n{}\r\n", arg.len(), arg);
+    }
+    out
+}
+
+/// Where the AOF stands: its length, and a digest of all its bytes.
+/// A snapshot taken at a position only matches an AOF starting with the same bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AofPosition {
+    pub offset: u64,
+    pub digest: u64,
+}
+
+impl Default for AofPosition {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            digest: FNV_OFFSET,
+        }
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+impl AofPosition {
+    /// Move past `bytes`, FNV-1a hashed so that the digest doesn't depend on how they are split
+    pub fn advance(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.digest = (self.digest ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+        self.offset += bytes.len() as u64;
+    }
+
+    /// The position after the first `len` bytes of `reader`, or its end if shorter
+    pub fn of(reader: impl Read, len: u64) -> std::io::Result<Self> {
+        let mut reader = reader.take(len);
+        let mut position = Self::default();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(position),
+                n => position.advance(&buf[..n]),
+            }
+        }
+    }
+}
+
+/// Told whether appended records reached the disk, or why they didn't
+pub type Ack = oneshot::Sender<Result<(), String>>;
+
+/// What the AOF writer is asked to do
+pub enum AofMessage {
+    /// Records to append, already encoded, with who waits for them to be synced
+    Append(String, Option<Ack>),
+    /// A rewrite from the data as of now started: buffer the writes from now on
+    RewriteStart,
+    /// The rewritten file at the path is ready, to be completed and swapped in
+    RewriteDone(String),
+    RewriteFailed,
+    /// Tell the position after the records sent so far, which a snapshot is taken at
+    Mark(oneshot::Sender<AofPosition>),
+    /// The AOF was loaded at boot, and maybe truncated: measure it again
+    Loaded,
+    Shutdown,
+}
+
+/// Sizes of the AOF, to decide when to rewrite it, and whether it can be written
+#[derive(Default)]
+pub struct AofStatus {
+    pub size: AtomicU64,
+    /// Size after the last rewrite, or when the server started
+    pub base_size: AtomicU64,
+    pub rewriting: AtomicBool,
+    /// Error of the last write or sync, if it failed
+    error: Mutex<Option<String>>,
+}
+
+impl AofStatus {
+    /// Why the AOF can't be written, while writes are refused
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+
+    fn set_error(&self, error: Option<String>) {
+        *self.error.lock().unwrap() = error;
+    }
+
+    /// Whether the AOF is at least `min_size` and grew by `percentage` since its base size
+    pub fn needs_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        let size = self.size.load(Ordering::Relaxed);
+        let base = self.base_size.load(Ordering::Relaxed).max(1);
+        percentage > 0
+            && size >= min_size
+            && size.saturating_sub(base) * 100 / base >= percentage
+            && !self.rewriting.load(Ordering::Relaxed)
+    }
+}
+
+/// Write the records of a rewrite to `path`
+pub fn write_rewrite(path: &str, records: impl Iterator<Item = Record>) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for record in records {
+        file.write_all(encode(&record).as_bytes())?;
+    }
+    file.into_inner()?.sync_all()
+}
+
+/// Complete the rewritten AOF at `temp` with the writes buffered meanwhile,
+/// and move it over the AOF at `path` in one step.
+/// return: the new AOF, opened for appending
+pub fn finish_rewrite(temp: &str, path: &str, buffered: &[String]) -> std::io::Result<File> {
+    let mut file = OpenOptions::new().append(true).open(temp)?;
+    for msg in buffered {
+        file.write_all(msg.as_bytes())?;
+    }
+    file.sync_all()?;
+    replace_file(temp, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Move the synced file at `temp` over the one at `path`, in one step
+pub(crate) fn replace_file(temp: &str, path: &str) -> std::io::Result<()> {
+    std::fs::rename(temp, path)?;
+    // Make the rename itself durable
+    if let Some(dir) = Path::new(path).parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Appends to the AOF, and syncs it as `appendfsync` says.
+/// Records are queued, then written in one go by `commit`, so that the writes arriving
+/// together share one write and one sync
+pub struct AofWriter {
+    path: String,
+    file: File,
+    policy: AppendFsync,
+    status: Arc<AofStatus>,
+    /// Records not written yet: queued since the last commit, or whose write failed
+    pending: String,
+    /// Whether records were written since the last sync
+    dirty: bool,
+    /// Waiting for the queued records to be synced
+    acks: Vec<Ack>,
+    /// Position after the queued records
+    position: AofPosition,
+    /// Writes since a rewrite started, to be appended to the rewritten file
+    rewrite_buf: Option<Vec<String>>,
+}
+
+impl AofWriter {
+    pub fn open(path: String, policy: AppendFsync, status: Arc<AofStatus>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let mut writer = Self {
+            path,
+            file,
+            policy,
+            status,
+            pending: String::new(),
+            dirty: false,
+            acks: Vec::new(),
+            position: AofPosition::default(),
+            rewrite_buf: None,
+        };
+        writer.measure()?;
+        Ok(writer)
+    }
+
+    /// Take the size and position of the file as it is on disk, then the records not written
+    fn measure(&mut self) -> std::io::Result<()> {
+        let size = self.file.metadata()?.len();
+        self.position = AofPosition::of(File::open(&self.path)?, size)?;
+        // Records whose write failed are still to come
+        self.position.advance(self.pending.as_bytes());
+        self.status.size.store(size, Ordering::Relaxed);
+        self.status.base_size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Position after the records appended so far, written or not
+    pub fn position(&self) -> AofPosition {
+        self.position
+    }
+
+    /// Measure the file again once it was loaded, as it may have been truncated meanwhile
+    pub fn reload(&mut self) {
+        self.commit();
+        if let Err(e) = self.measure() {
+            tracing::error!("Failed to measure the AOF: {}", e);
+        }
+    }
+
+    pub fn append(&mut self, records: String, ack: Option<Ack>) {
+        if let Some(buf) = self.rewrite_buf.as_mut() {
+            buf.push(records.clone());
+        }
+        self.position.advance(records.as_bytes());
+        self.pending += &records;
+        self.acks.extend(ack);
+    }
+
+    /// Write the queued records, and under `always` sync them, then tell the ones waiting.
+    /// On failure the records stay queued, to be written again by the next commit or tick
+    pub fn commit(&mut self) {
+        if self.pending.is_empty() && self.acks.is_empty() {
+            return;
+        }
+        let mut result = self.write_pending();
+        if result.is_ok() && self.policy == AppendFsync::Always {
+            result = self.sync();
+        }
+        let result = result.map_err(|e| e.to_string());
+        for ack in self.acks.drain(..) {
+            let _ = ack.send(result.clone());
+        }
+    }
+
+    /// Once a second: write again what failed, and sync under `everysec`
+    pub fn tick(&mut self) {
+        self.commit();
+        if self.policy == AppendFsync::Everysec && self.dirty {
+            let _ = self.sync();
+        }
+    }
+
+    /// Write and sync everything before the server exits
+    pub fn shutdown(&mut self) {
+        self.commit();
+        if self.dirty {
+            let _ = self.sync();
+        }
+    }
+
+    /// A rewrite from the data as of now started
+    pub fn start_rewrite(&mut self) {
+        self.rewrite_buf = Some(Vec::new());
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buf = None;
+        self.status.rewriting.store(false, Ordering::Relaxed);
+    }
+
+    /// Swap in the rewritten AOF at `temp`, completed with the writes made meanwhile
+    pub fn finish_rewrite(&mut self, temp: &str) {
+        self.commit();
+        let buf = self.rewrite_buf.take().unwrap_or_default();
+        match finish_rewrite(temp, &self.path, &buf) {
+            Ok(file) => {
+                self.file = file;
+                // The new file holds all the records, including those whose write failed
+                self.pending.clear();
+                self.dirty = false;
+                self.status.set_error(None);
+                if let Err(e) = self.measure() {
+                    tracing::error!("Failed to measure the AOF: {}", e);
+                }
+                tracing::info!("AOF rewritten, {} bytes", self.position.offset);
+            }
+            Err(e) => {
+                tracing::error!("Failed to complete the AOF rewrite: {}", e);
+                let _ = std::fs::remove_file(temp);
+            }
+        }
+        self.status.rewriting.store(false, Ordering::Relaxed);
+    }
+
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let size = self.status.size.load(Ordering::Relaxed);
+        let result = self.file.write_all(self.pending.as_bytes());
+        if let Err(e) = result {
+            // Drop the part written, so that writing again doesn't leave half a record
+            let _ = self.file.set_len(size);
+            return Err(self.failed("write", e));
+        }
+        self.status.size.fetch_add(self.pending.len() as u64, Ordering::Relaxed);
+        self.pending.clear();
+        self.dirty = true;
+        // Otherwise the writes are durable again only once synced
+        if self.policy == AppendFsync::No {
+            self.status.set_error(None);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if let Err(e) = self.file.sync_data() {
+            return Err(self.failed("sync", e));
+        }
+        self.dirty = false;
+        self.status.set_error(None);
+        Ok(())
+    }
+
+    /// Refuse the writes until the AOF can be written again
+    fn failed(&self, action: &str, e: std::io::Error) -> std::io::Error {
+        tracing::error!("Failed to {} the AOF: {}", action, e);
+        self.status.set_error(Some(e.to_string()));
+        e
+    }
+}
+
+#[derive(Debug)]
+pub enum AofError {
+    /// The file ends in the middle of the record starting at `offset`, as after a crash during a write
+    Truncated {
+        offset: u64,
+    },
+    /// The bytes at `offset` are not a record
+    Corrupt {
+        offset: u64,
+        reason: String,
+    },
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for AofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AofError::Truncated { offset } => write!(f, "truncated record at offset {offset}"),
+            AofError::Corrupt { offset, reason } => {
+                write!(f, "bad record at offset {offset}: {reason}")
+            }
+            AofError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for AofError {}
+
+pub struct AofReader<R> {
+    reader: R,
+    /// End of the last record read
+    offset: u64,
+}
+
+impl<R: BufRead> AofReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::starting_at(reader, 0)
+    }
+
+    /// Read from `offset` of the file, where `reader` already is
+    pub fn starting_at(reader: R, offset: u64) -> Self {
+        Self { reader, offset }
+    }
+
+    /// Bytes of the file made of complete records read so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next record, None at the end of the file
+    pub fn next_record(&mut self) -> Result<Option<Record>, AofError> {
+        loop {
+            let start = self.offset;
+            let mut read = 0;
+            let Some(line) = self.read_line(start, &mut read)? else {
+                return Ok(None);
+            };
+            let record = match line.strip_prefix('*') {
+                Some(argc) => {
+                    let argc = parse_len(argc, start)?;
+                    let mut record = Vec::with_capacity(argc);
+                    for _ in 0..argc {
+                        record.push(self.read_arg(start, &mut read)?);
+                    }
+                    Some(record)
+                }
+                // Old format, where blank lines are skipped
+                None if line.is_empty() => None,
+                None => Some(old_record(line.split(' ').map(String::from).collect())),
+            };
+            self.offset = start + read;
+            if record.is_some() {
+                return Ok(record);
+            }
+        }
+    }
+
+    /// A line without its terminator, None at the end of the file
+    fn read_line(&mut self, start: u64, read: &mut u64) -> Result<Option<String>, AofError> {
+        let mut line = Vec::new();
+        let n = self
+            .reader
+            .read_until(b'\n', &mut line)
+            .map_err(AofError::Io)?;
+        if n == 0 && *read == 0 {
+            return Ok(None);
+        }
+        *read += n as u64;
+        if line.pop() != Some(b'\n') {
+            return Err(AofError::Truncated { offset: start });
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| corrupt(start, "invalid UTF-8"))
+    }
+
+    /// `$<len>\r\n<arg>\r\n`
+    fn read_arg(&mut self, start: u64, read: &mut u64) -> Result<String, AofError> {
+        let header = self
+            .read_line(start, read)?
+            .ok_or(AofError::Truncated { offset: start })?;
+        let len = header
+            .strip_prefix('$')
+            .ok_or_else(|| corrupt(start, "expected `$`"))?;
+        let len = parse_len(len, start)?;
+        if len > MAX_ARG_LEN {
+            return Err(corrupt(start, "argument too long"));
+        }
+        let mut arg = vec![0; len + 2];
+        self.reader
+            .read_exact(&mut arg)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => AofError::Truncated { offset: start },
+                _ => AofError::Io(e),
+            })?;
+        *read += arg.len() as u64;
+        if !arg.ends_with(b"\r\n") {
+            return Err(corrupt(start, "argument longer than its length"));
+        }
+        arg.truncate(len);
+        String::from_utf8(arg).map_err(|_| corrupt(start, "invalid UTF-8"))
+    }
+}
+
+/// The record of today for a line of the old format, where a SET ends with its deadline
+/// in milliseconds, 0 for none, and a DEL of one key with two zeros
+fn old_record(mut line: Record) -> Record {
+    match line.as_slice() {
+        [cmd, _, _, at] if cmd == "SET" && at.parse::<u128>().is_ok() => {
+            let at = line.pop().unwrap();
+            if at.parse::<u128>() != Ok(0) {
+                line.extend(["PXAT".to_string(), at]);
+            }
+        }
+        [cmd, _, zero, zero2] if cmd == "DEL" && zero == "0" && zero2 == "0" => line.truncate(2),
+        _ => {}
+    }
+    line
+}
+
+fn parse_len(len: &str, offset: u64) -> Result<usize, AofError> {
+    len.parse()
+        .map_err(|_| corrupt(offset, &format!("invalid length `{len}`")))
+}
+
+fn corrupt(offset: u64, reason: &str) -> AofError {
+    AofError::Corrupt {
+        offset,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::replay;
+    use crate::redis::Redis;
+
+    fn record(args: &[&str]) -> Record {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn read_all(bytes: &[u8]) -> (Vec<Record>, Option<AofError>, u64) {
+        let mut reader = AofReader::new(bytes);
+        let mut records = Vec::new();
+        loop {
+            match reader.next_record() {
+                Ok(Some(record)) => records.push(record),
+                Ok(None) => return (records, None, reader.offset()),
+                Err(e) => return (records, Some(e), reader.offset()),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_back_encoded_records() {
+        let records = vec![
+            record(&["SET", "key with spaces", "line\r\nbreak"]),
+            record(&["SET", "", "ünïcode"]),
+            record(&["DEL", "a", "b"]),
+        ];
+        let bytes: String = records.iter().map(|record| encode(record)).collect();
+        let (read, error, offset) = read_all(bytes.as_bytes());
+        assert_eq!(read, records);
+        assert!(error.is_none());
+        assert_eq!(offset, bytes.len() as u64);
+    }
+
+    #[test]
+    fn truncated_tail() {
+        let first = encode(&record(&["SET", "a", "1"]));
+        let second = encode(&record(&["SET", "b", "2"]));
+        // Cut anywhere in the second record
+        for cut in 1..second.len() {
+            let bytes = format!("{first}{}", &second[..cut]);
+            let (read, error, offset) = read_all(bytes.as_bytes());
+            assert_eq!(read, vec![record(&["SET", "a", "1"])], "cut at {cut}");
+            assert!(
+                matches!(error, Some(AofError::Truncated { offset }) if offset == first.len() as u64),
+                "cut at {cut}: {error:?}"
+            );
+            assert_eq!(offset, first.len() as u64);
+        }
+    }
+
+    #[test]
+    fn corrupt_records() {
+        let first = encode(&record(&["SET", "a", "1"]));
+        let cases = [
+            "*x\r\n",
+            "*1\r\nfoo\r\n",
+            "*1\r\n$x\r\nfoo\r\n",
+            "*1\r\n$2\r\nfoo\r\n",
+        ];
+        for case in cases {
+            let mut bytes = first.as_bytes().to_vec();
+            bytes.extend(case.as_bytes());
+            let (read, error, offset) = read_all(&bytes);
+            assert_eq!(read.len(), 1, "{case:?}");
+            assert!(
+                matches!(error, Some(AofError::Corrupt { offset, .. }) if offset == first.len() as u64),
+                "{case:?}: {error:?}"
+            );
+            assert_eq!(offset, first.len() as u64);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_is_corrupt() {
+        let (read, error, _) = read_all(b"*1\r\n$1\r\n\xff\r\n");
+        assert!(read.is_empty());
+        assert!(matches!(error, Some(AofError::Corrupt { offset: 0, .. })));
+    }
+
+    #[test]
+    fn too_long_argument_is_corrupt() {
+        let bytes = format!("*1\r\n${}\r\n", MAX_ARG_LEN + 1);
+        let (_, error, _) = read_all(bytes.as_bytes());
+        assert!(matches!(error, Some(AofError::Corrupt { offset: 0, .. })));
+    }
+
+    #[test]
+    fn old_line_format() {
+        // As the old server wrote them
+        let bytes = "SET a 1 0\n\nSET b 2 1700000000000\nDEL a 0 0\r\n";
+        let (read, error, offset) = read_all(bytes.as_bytes());
+        assert_eq!(
+            read,
+            vec![
+                record(&["SET", "a", "1"]),
+                record(&["SET", "b", "2", "PXAT", "1700000000000"]),
+                record(&["DEL", "a"]),
+            ]
+        );
+        assert!(error.is_none());
+        assert_eq!(offset, bytes.len() as u64);
+        // A last line without its newline was cut by a crash
+        let (read, error, offset) = read_all(b"SET a 1 0\nSET b");
+        assert_eq!(read, vec![record(&["SET", "a", "1"])]);
+        assert!(matches!(error, Some(AofError::Truncated { offset: 10 })));
+        assert_eq!(offset, 10);
+    }
+
+    #[test]
+    fn old_lines_replayed() {
+        let later = Redis::now() + 60_000;
+        let bytes = format!("SET 0 zero 0\nSET a 1 0\nSET b 2 {later}\nSET c 3 1000\nDEL a 0 0\n");
+        let mut redis = Redis::new(1);
+        for record in read_all(bytes.as_bytes()).0 {
+            replay(&mut redis, &record).unwrap();
+        }
+        // Only the key named is deleted, not one named `0`
+        assert_eq!(redis.get("0").unwrap().as_deref(), Some("zero"));
+        assert_eq!(redis.get("a").unwrap(), None);
+        assert_eq!(redis.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(redis.expiry("b"), Some(later));
+        assert_eq!(redis.expiry("0"), None);
+        // Its deadline passed long ago
+        assert_eq!(redis.get("c").unwrap(), None);
+    }
+
+    #[test]
+    fn old_and_new_formats_mixed() {
+        let bytes = format!("SET a 1\n{}", encode(&record(&["SET", "b", "2 3"])));
+        let (read, error, _) = read_all(bytes.as_bytes());
+        assert_eq!(
+            read,
+            vec![record(&["SET", "a", "1"]), record(&["SET", "b", "2 3"])]
+        );
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn starting_at_counts_from_the_offset() {
+        let bytes = encode(&record(&["SET", "b", "2"]));
+        let mut reader = AofReader::starting_at(bytes.as_bytes(), 100);
+        assert!(reader.next_record().unwrap().is_some());
+        assert_eq!(reader.offset(), 100 + bytes.len() as u64);
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn digest_does_not_depend_on_the_split() {
+        let bytes = b"*1\r\n$4\r\nPING\r\n";
+        let mut split = AofPosition::default();
+        split.advance(&bytes[..5]);
+        split.advance(&bytes[5..]);
+        assert_eq!(AofPosition::of(&bytes[..], u64::MAX).unwrap(), split);
+        let prefix = AofPosition::of(&bytes[..], 5).unwrap();
+        assert_eq!(prefix.offset, 5);
+        assert_ne!(prefix, split);
+    }
+}
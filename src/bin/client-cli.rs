@@ -2,6 +2,7 @@ use clap::{Parser, Subcommand};
 use colored::Colorize;
 use lazy_static::lazy_static;
 use mini_redis::cmdargs::{self, ClientConfig};
+use mini_redis::command::parse_command;
 use mini_redis::{AsciiFilterLayer, TimedLayer};
 use pilota::FastStr;
 use rustyline::{error::ReadlineError, DefaultEditor};
@@ -25,10 +26,8 @@ enum Commands {
         key: String,
         /// value
         value: String,
-        // type
-        ttype: Option<String>,
-        // time
-        tnum: Option<u128>,
+        /// options: NX, XX, GET, KEEPTTL, EX/PX/EXAT/PXAT <time>, in any order
+        options: Vec<String>,
         // transaction_id
         #[clap(short, long)]
         transaction_id: Option<String>,
@@ -69,6 +68,9 @@ enum Commands {
     Multi,
     /// execute a transaction
     Exec,
+    /// any other command, e.g. `lpush mylist a b c`
+    #[command(external_subcommand)]
+    Other(Vec<String>),
 }
 
 lazy_static! {
@@ -95,6 +97,7 @@ async fn subscribe(handle: String) -> ! {
                 args: Some(vec![handle.clone().into()]),
                 client_id: None,
                 transaction_id: None,
+                db: None,
             })
             .await;
         match resp {
@@ -110,6 +113,24 @@ async fn subscribe(handle: String) -> ! {
     }
 }
 
+/// Print an array reply the way redis-cli does, one numbered line per element
+fn print_items(items: &[GetItemResponse], depth: usize) {
+    let indent = "   ".repeat(depth);
+    if items.is_empty() {
+        println!("{indent}(empty array)");
+    }
+    for (i, item) in items.iter().enumerate() {
+        match (&item.items, &item.data) {
+            (Some(inner), _) => {
+                println!("{indent}{})", i + 1);
+                print_items(inner, depth + 1);
+            }
+            (None, Some(data)) => println!("{indent}{}) {}", i + 1, data),
+            (None, None) => println!("{indent}{}) (nil)", i + 1),
+        }
+    }
+}
+
 #[volo::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -118,8 +139,14 @@ async fn main() {
     let mut state: String = "connected".into();
     let mut cmdline = DefaultEditor::new().expect("==command line failure==");
     let mut local_transaction_id: Option<String> = None;
+    // Database selected by SELECT, sent with every request
+    let mut db: Option<i32> = None;
     loop {
-        let line = match cmdline.readline(format!("vodis[{}]>  ", state.clone()).as_ref()) {
+        let prompt = match db {
+            Some(db) if db != 0 => format!("vodis[{}][{}]>  ", state, db),
+            _ => format!("vodis[{}]>  ", state),
+        };
+        let line = match cmdline.readline(prompt.as_ref()) {
             Ok(line) => line,
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
                 println!("Bye~~~");
@@ -156,10 +183,15 @@ async fn main() {
                 },
                 if let Some(str_) = resp.data {
                     str_.to_string()
+                } else if resp.items.is_some() {
+                    "".into()
                 } else {
                     "(nil)".into()
                 }
-            )
+            );
+            if let Some(items) = resp.items {
+                print_items(&items, 0);
+            }
         };
         let cli = cli.unwrap();
         match cli.command {
@@ -170,6 +202,7 @@ async fn main() {
                         args: args.map(|vstr| vstr.into_iter().map(|s| FastStr::new(s)).collect()),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -183,23 +216,18 @@ async fn main() {
             Commands::Set {
                 key,
                 value,
-                ttype,
-                tnum,
+                options,
                 transaction_id,
             } => {
-                let mut args = vec![key.into(), value.into()];
-                if ttype.is_some() {
-                    args.push(ttype.unwrap());
-                }
-                if tnum.is_some() {
-                    args.push(tnum.unwrap().to_string());
-                }
+                let mut args = vec![key, value];
+                args.extend(options);
                 let resp = CLIENT
                     .get_item(volo_gen::volo::redis::GetItemRequest {
                         cmd: RedisCommand::Set,
                         args: Some(args.iter().map(|s| FastStr::new(s)).collect()),
                         client_id: None,
                         transaction_id: transaction_id.clone().map(|s| FastStr::new(s)),
+                        db,
                     })
                     .await;
                 match resp {
@@ -220,6 +248,7 @@ async fn main() {
                         args: Some(vec![key.into()]),
                         client_id: None,
                         transaction_id: transaction_id.map(|s| s.into()),
+                        db,
                     })
                     .await;
                 match resp {
@@ -237,6 +266,7 @@ async fn main() {
                         args: Some(vec![key.into()]),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -259,6 +289,7 @@ async fn main() {
                         args: Some(vec![channel.into(), message.into()]),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -278,6 +309,7 @@ async fn main() {
                         args: Some(vec![channel.clone().into()]),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -307,6 +339,7 @@ async fn main() {
                         args: Some(vec![key.into()]),
                         client_id: None,
                         transaction_id: local_transaction_id.clone().map(|s| s.into()),
+                        db,
                     })
                     .await;
                 match resp {
@@ -327,6 +360,7 @@ async fn main() {
                         args: None,
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -349,6 +383,7 @@ async fn main() {
                         args: None,
                         client_id: None,
                         transaction_id: local_transaction_id.clone().map(|s| s.into()),
+                        db,
                     })
                     .await;
                 match resp {
@@ -363,6 +398,31 @@ async fn main() {
                 }
                 continue;
             }
+            Commands::Other(args) => {
+                let Some(cmd) = parse_command(&args[0]) else {
+                    println!("{}Unknown command `{}`", "[FAILED] ".red(), args[0]);
+                    continue;
+                };
+                let resp = CLIENT
+                    .get_item(volo_gen::volo::redis::GetItemRequest {
+                        cmd,
+                        args: Some(args[1..].iter().map(FastStr::new).collect()),
+                        client_id: None,
+                        transaction_id: None,
+                        db,
+                    })
+                    .await;
+                match resp {
+                    Ok(info) => {
+                        if cmd == RedisCommand::Select && info.ok {
+                            db = args[1].parse().ok();
+                        }
+                        colored_out(info);
+                    }
+                    Err(e) => tracing::error!("{:?}", e),
+                }
+                continue;
+            }
         };
     }
 }
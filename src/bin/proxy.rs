@@ -2,6 +2,7 @@ use clap::{Parser, Subcommand};
 use colored::Colorize;
 use lazy_static::lazy_static;
 use mini_redis::cmdargs::{self, ProxyConfig};
+use mini_redis::command::{command_keys, command_name, is_write, parse_command};
 use mini_redis::{AsciiFilterLayer, TimedLayer};
 use pilota::FastStr;
 use rustyline::{error::ReadlineError, DefaultEditor};
@@ -29,10 +30,8 @@ enum Commands {
         key: String,
         /// value
         value: String,
-        // type
-        ttype: Option<String>,
-        // time
-        tnum: Option<u128>,
+        /// options: NX, XX, GET, KEEPTTL, EX/PX/EXAT/PXAT <time>, in any order
+        options: Vec<String>,
         // transaction_id
         #[clap(short, long)]
         transaction_id: Option<String>,
@@ -73,6 +72,9 @@ enum Commands {
     Multi,
     /// execute a transaction
     Exec,
+    /// any other command, e.g. `lpush mylist a b c`
+    #[command(external_subcommand)]
+    Other(Vec<String>),
 }
 
 lazy_static! {
@@ -101,6 +103,7 @@ async fn subscribe(handle: String) -> ! {
                 args: Some(vec![handle.clone().into()]),
                 client_id: None,
                 transaction_id: None,
+                db: None,
             })
             .await;
         match resp {
@@ -116,8 +119,234 @@ async fn subscribe(handle: String) -> ! {
     }
 }
 const SLOTS: usize = 16384;
+
+/// Slot of a key. With `--hash-tags`, only the part inside the first non-empty `{...}`
+/// is hashed if there is one, so that related keys, e.g. `{user:1}:following` and
+/// `{user:1}:followers`, share a slot.
+fn key_slot(key: &str) -> usize {
+    let tagged = key
+        .find('{')
+        .filter(|_| CMD_ARGS.hash_tags)
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            tag.find('}').map(|close| &tag[..close])
+        })
+        .filter(|tag| !tag.is_empty());
+    let mut hasher = DefaultHasher::new();
+    tagged.unwrap_or(key).hash(&mut hasher);
+    (hasher.finish() % SLOTS as u64) as usize
+}
 use std::collections::HashMap;
 
+/// MGET / MSET across the cluster: the keys are grouped by the master owning their slot,
+/// one request per master is sent in parallel, and the replies are put back in request order
+async fn fan_out(
+    cmd: RedisCommand,
+    args: &[String],
+    db: Option<i32>,
+    owner: impl Fn(&str) -> SocketAddr,
+) -> Result<GetItemResponse, String> {
+    // MSET takes key-value pairs
+    let step = if cmd == RedisCommand::MSet { 2 } else { 1 };
+    if args.is_empty() || !args.len().is_multiple_of(step) {
+        return Err(format!(
+            "wrong number of arguments for '{}' command",
+            command_name(cmd).to_lowercase()
+        ));
+    }
+    let mut groups: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+    for (i, chunk) in args.chunks(step).enumerate() {
+        groups.entry(owner(&chunk[0])).or_default().push(i);
+    }
+    let requests: Vec<_> = groups
+        .into_iter()
+        .map(|(addr, indices)| {
+            let group_args = indices
+                .iter()
+                .flat_map(|i| args[i * step..(i + 1) * step].iter().map(FastStr::new))
+                .collect();
+            let request = volo_gen::volo::redis::GetItemRequest {
+                cmd,
+                args: Some(group_args),
+                client_id: None,
+                transaction_id: None,
+                db,
+            };
+            info!("proxyed to {}.", addr.to_string());
+            let handle = tokio::spawn(async move { get_client(addr).get_item(request).await });
+            (indices, handle)
+        })
+        .collect();
+    let mut items = vec![GetItemResponse::default(); args.len() / step];
+    for (indices, handle) in requests {
+        let resp = handle
+            .await
+            .map_err(|e| format!("{:?}", e))?
+            .map_err(|e| format!("{:?}", e))?;
+        for (i, item) in indices.into_iter().zip(resp.items.unwrap_or_default()) {
+            items[i] = item;
+        }
+    }
+    Ok(match cmd {
+        RedisCommand::MSet => GetItemResponse {
+            ok: true,
+            data: Some("OK".into()),
+            items: None,
+        },
+        _ => GetItemResponse {
+            ok: true,
+            data: None,
+            items: Some(items),
+        },
+    })
+}
+
+/// Whether a command without keys acts on the data of every master, rather than on one node
+fn is_cluster_wide(cmd: RedisCommand) -> bool {
+    matches!(
+        cmd,
+        RedisCommand::DbSize
+            | RedisCommand::Keys
+            | RedisCommand::RandomKey
+            | RedisCommand::SwapDb
+            | RedisCommand::FlushDb
+            | RedisCommand::FlushAll
+            | RedisCommand::Save
+            | RedisCommand::BgSave
+            | RedisCommand::BgRewriteAof
+            | RedisCommand::LastSave
+    )
+}
+
+/// A command without keys sent to every master in parallel, and their replies combined:
+/// DBSIZE adds up, KEYS puts the keys together, RANDOMKEY takes the key of a random master
+/// holding some, and LASTSAVE the oldest save. Others reply as the masters did, or with the
+/// first failure
+async fn broadcast(
+    cmd: RedisCommand,
+    args: &[String],
+    db: Option<i32>,
+    masters: &[SocketAddr],
+) -> Result<GetItemResponse, String> {
+    let handles: Vec<_> = masters
+        .iter()
+        .map(|addr| {
+            let request = volo_gen::volo::redis::GetItemRequest {
+                cmd,
+                args: Some(args.iter().map(FastStr::new).collect()),
+                client_id: None,
+                transaction_id: None,
+                db,
+            };
+            info!("proxyed to {}.", addr.to_string());
+            let addr = *addr;
+            tokio::spawn(async move { get_client(addr).get_item(request).await })
+        })
+        .collect();
+    let mut replies = Vec::with_capacity(handles.len());
+    for handle in handles {
+        let resp = handle
+            .await
+            .map_err(|e| format!("{:?}", e))?
+            .map_err(|e| format!("{:?}", e))?;
+        replies.push(resp);
+    }
+    let int = |resp: &GetItemResponse| {
+        resp.data
+            .as_ref()
+            .and_then(|data| data.parse::<i64>().ok())
+            .ok_or(format!("bad reply to {}", command_name(cmd)))
+    };
+    Ok(match cmd {
+        RedisCommand::DbSize | RedisCommand::LastSave => {
+            let values = replies.iter().map(int).collect::<Result<Vec<_>, _>>()?;
+            let value = match cmd {
+                RedisCommand::DbSize => values.iter().sum(),
+                _ => values.into_iter().min().unwrap_or(0),
+            };
+            GetItemResponse {
+                ok: true,
+                data: Some(value.to_string().into()),
+                items: None,
+            }
+        }
+        RedisCommand::Keys => GetItemResponse {
+            ok: true,
+            data: None,
+            items: Some(
+                replies
+                    .into_iter()
+                    .flat_map(|resp| resp.items.unwrap_or_default())
+                    .collect(),
+            ),
+        },
+        RedisCommand::RandomKey => {
+            let mut found: Vec<_> = replies
+                .into_iter()
+                .filter(|resp| resp.data.is_some())
+                .collect();
+            match found.len() {
+                0 => GetItemResponse::default(),
+                n => found.swap_remove(rand::random::<usize>() % n),
+            }
+        }
+        _ => replies
+            .iter()
+            .find(|resp| !resp.ok)
+            .or(replies.first())
+            .cloned()
+            .unwrap_or_default(),
+    })
+}
+
+/// Cluster-wide SCAN, iterating the masters one after another.
+/// The cursor holds the index of the master in its high 64 bits, and the cursor
+/// within that master in the low ones
+async fn scan_cluster(
+    args: &[String],
+    db: Option<i32>,
+    masters: &[SocketAddr],
+) -> Result<GetItemResponse, String> {
+    let cursor = args
+        .first()
+        .and_then(|cursor| cursor.parse::<u128>().ok())
+        .ok_or("invalid cursor".to_string())?;
+    let node = (cursor >> 64) as usize;
+    let Some(addr) = masters.get(node) else {
+        return Err("invalid cursor".to_string());
+    };
+    let mut node_args: Vec<FastStr> = vec![(cursor as u64).to_string().into()];
+    node_args.extend(args[1..].iter().map(FastStr::new));
+    info!("proxyed to {}.", addr.to_string());
+    let resp = get_client(*addr)
+        .get_item(volo_gen::volo::redis::GetItemRequest {
+            cmd: RedisCommand::Scan,
+            args: Some(node_args),
+            client_id: None,
+            transaction_id: None,
+            db,
+        })
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let mut items = resp.items.unwrap_or_default();
+    let next: u64 = items
+        .first()
+        .and_then(|item| item.data.as_ref()?.parse().ok())
+        .ok_or("bad reply to SCAN".to_string())?;
+    // Done with this master, continue with the next one if any
+    let next = match next {
+        0 if node + 1 < masters.len() => ((node + 1) as u128) << 64,
+        0 => 0,
+        next => ((node as u128) << 64) | next as u128,
+    };
+    items[0].data = Some(next.to_string().into());
+    Ok(GetItemResponse {
+        ok: true,
+        data: None,
+        items: Some(items),
+    })
+}
+
 pub struct CountingBloomFilter {
     filter: HashMap<String, i32>,
     num_hash_functions: usize,
@@ -172,6 +401,24 @@ impl CountingBloomFilter {
         hashes
     }
 }
+/// Print an array reply the way redis-cli does, one numbered line per element
+fn print_items(items: &[GetItemResponse], depth: usize) {
+    let indent = "   ".repeat(depth);
+    if items.is_empty() {
+        println!("{indent}(empty array)");
+    }
+    for (i, item) in items.iter().enumerate() {
+        match (&item.items, &item.data) {
+            (Some(inner), _) => {
+                println!("{indent}{})", i + 1);
+                print_items(inner, depth + 1);
+            }
+            (None, Some(data)) => println!("{indent}{}) {}", i + 1, data),
+            (None, None) => println!("{indent}{}) (nil)", i + 1),
+        }
+    }
+}
+
 #[volo::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -200,20 +447,20 @@ async fn main() {
         slot_belong.push(i / each_node);
     }
 
-    let hashed_client = |key: &str| -> volo_gen::volo::redis::ItemServiceClient {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash_result = hasher.finish();
-        let id = slot_belong[(hash_result % SLOTS as u64) as usize];
+    let slot_client = |slot: usize| -> volo_gen::volo::redis::ItemServiceClient {
+        let id = slot_belong[slot];
         info!("proxyed to {}.", masters[id].to_string());
         get_client(masters[id])
     };
+    let hashed_client = |key: &str| slot_client(key_slot(key));
 
     // ==================
 
     let mut state: String = "cluster:proxy".into();
     let mut cmdline = DefaultEditor::new().expect("==command line failure==");
     let mut local_transaction_id: Option<String> = None;
+    // Database selected by SELECT, sent with every request
+    let mut db: Option<i32> = None;
     loop {
         let line = match cmdline.readline(format!("vodis[{}]>  ", state.clone()).as_ref()) {
             Ok(line) => line,
@@ -252,10 +499,15 @@ async fn main() {
                 },
                 if let Some(str_) = resp.data {
                     str_.to_string()
+                } else if resp.items.is_some() {
+                    "".into()
                 } else {
                     "(nil)".into()
                 }
-            )
+            );
+            if let Some(items) = resp.items {
+                print_items(&items, 0);
+            }
         };
         let cli = cli.unwrap();
         match cli.command {
@@ -266,6 +518,7 @@ async fn main() {
                         args: args.map(|vstr| vstr.into_iter().map(|s| FastStr::new(s)).collect()),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -280,17 +533,11 @@ async fn main() {
                 // ! PROXYED
                 key,
                 value,
-                ttype,
-                tnum,
+                options,
                 transaction_id,
             } => {
-                let mut args = vec![key.into(), value.into()];
-                if ttype.is_some() {
-                    args.push(ttype.unwrap());
-                }
-                if tnum.is_some() {
-                    args.push(tnum.unwrap().to_string());
-                }
+                let mut args = vec![key, value];
+                args.extend(options);
                 bloom_filter.insert(args[0].clone());
                 let resp = hashed_client(&args[0])
                     .get_item(volo_gen::volo::redis::GetItemRequest {
@@ -298,6 +545,7 @@ async fn main() {
                         args: Some(args.iter().map(|s| FastStr::new(s)).collect()),
                         client_id: None,
                         transaction_id: transaction_id.clone().map(|s| FastStr::new(s)),
+                        db,
                     })
                     .await;
                 match resp {
@@ -317,6 +565,7 @@ async fn main() {
                     let info = GetItemResponse {
                         ok: false,
                         data: Some("(nil)".into()),
+                        items: None,
                     };
                     colored_out(info);
                     info!("(by Bloom)");
@@ -328,6 +577,7 @@ async fn main() {
                         args: Some(vec![key.into()]),
                         client_id: None,
                         transaction_id: transaction_id.map(|s| s.into()),
+                        db,
                     })
                     .await;
                 match resp {
@@ -346,6 +596,7 @@ async fn main() {
                         args: Some(vec![key.clone().into()]),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -373,6 +624,7 @@ async fn main() {
                         args: Some(vec![channel.into(), message.into()]),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -392,6 +644,7 @@ async fn main() {
                         args: Some(vec![channel.clone().into()]),
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -421,6 +674,7 @@ async fn main() {
                         args: Some(vec![key.into()]),
                         client_id: None,
                         transaction_id: local_transaction_id.clone().map(|s| s.into()),
+                        db,
                     })
                     .await;
                 match resp {
@@ -441,6 +695,7 @@ async fn main() {
                         args: None,
                         client_id: None,
                         transaction_id: None,
+                        db,
                     })
                     .await;
                 match resp {
@@ -463,6 +718,7 @@ async fn main() {
                         args: None,
                         client_id: None,
                         transaction_id: local_transaction_id.clone().map(|s| s.into()),
+                        db,
                     })
                     .await;
                 match resp {
@@ -477,6 +733,78 @@ async fn main() {
                 }
                 continue;
             }
+            Commands::Other(args) => {
+                // ! PROXYED, all keys must be in the same slot
+                let Some(cmd) = parse_command(&args[0]) else {
+                    println!("{}Unknown command `{}`", "[FAILED] ".red(), args[0]);
+                    continue;
+                };
+                let keys = command_keys(cmd, &args[1..]);
+                if cmd == RedisCommand::Scan {
+                    match scan_cluster(&args[1..], db, &masters).await {
+                        Ok(info) => colored_out(info),
+                        Err(e) => println!("{}{}", "[FAILED] ".red(), e),
+                    }
+                    continue;
+                }
+                if is_cluster_wide(cmd) {
+                    match broadcast(cmd, &args[1..], db, &masters).await {
+                        Ok(info) => colored_out(info),
+                        Err(e) => println!("{}{}", "[FAILED] ".red(), e),
+                    }
+                    continue;
+                }
+                // MSETNX stays single-slot, its all-or-nothing check can't span masters
+                if cmd == RedisCommand::MGet || cmd == RedisCommand::MSet {
+                    if cmd == RedisCommand::MSet {
+                        for key in keys.iter() {
+                            bloom_filter.insert(key.to_string());
+                        }
+                    }
+                    let owner = |key: &str| masters[slot_belong[key_slot(key)]];
+                    match fan_out(cmd, &args[1..], db, owner).await {
+                        Ok(info) => colored_out(info),
+                        Err(e) => println!("{}{}", "[FAILED] ".red(), e),
+                    }
+                    continue;
+                }
+                let slots: Vec<usize> = keys.iter().map(|key| key_slot(key)).collect();
+                if slots.iter().any(|slot| *slot != slots[0]) {
+                    println!(
+                        "{}CROSSSLOT Keys in request don't hash to the same slot",
+                        "[FAILED] ".red()
+                    );
+                    continue;
+                }
+                if is_write(cmd) {
+                    for key in keys.iter() {
+                        bloom_filter.insert(key.to_string());
+                    }
+                }
+                let client = match slots.first() {
+                    Some(slot) => slot_client(*slot),
+                    None => TO_PROXY_SELF.clone(),
+                };
+                let resp = client
+                    .get_item(volo_gen::volo::redis::GetItemRequest {
+                        cmd,
+                        args: Some(args[1..].iter().map(FastStr::new).collect()),
+                        client_id: None,
+                        transaction_id: None,
+                        db,
+                    })
+                    .await;
+                match resp {
+                    Ok(info) => {
+                        if cmd == RedisCommand::Select && info.ok {
+                            db = args[1].parse().ok();
+                        }
+                        colored_out(info);
+                    }
+                    Err(e) => tracing::error!("{:?}", e),
+                }
+                continue;
+            }
         };
     }
 }
@@ -1,14 +1,16 @@
 #![feature(impl_trait_in_assoc_type)]
 use clap::Parser;
 use lazy_static::lazy_static;
+use mini_redis::aof::{AofError, AofPosition, AofReader};
 use mini_redis::cmdargs::ServerConfig;
+use mini_redis::command::replay;
+use mini_redis::snapshot;
 
-use mini_redis::S;
+use mini_redis::{aof_path, snapshot_path, S};
 use mini_redis::{AsciiFilterLayer, TimedLayer};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Seek, SeekFrom};
 use std::net::SocketAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
 lazy_static! {
     // Command line args
     static ref CMD_ARGS: ServerConfig = ServerConfig::parse();
@@ -21,42 +23,21 @@ async fn main() {
         CMD_ARGS.port.to_string().parse().unwrap(),
     );
     let addr = volo::net::Address::from(addr);
-    let name = CMD_ARGS.name.clone();
     let s = S::new().await;
-    let file = File::open(format!("{}.aof", name.unwrap())).unwrap();
-    let reader = BufReader::new(file);
 
-    for line in reader.lines() {
-        let line = line.unwrap();
-        let parts: Vec<&str> = line.trim().splitn(4, ' ').collect();
-
-        if parts.len() != 4 {
-            eprintln!("Invalid command: {}", line);
-            continue;
-        }
-
-        let command = parts[0];
-        let id = parts[1];
-        let title = parts[2];
-        let miliseconds = parts[3].parse::<u128>().unwrap();
-        match command {
-            "SET" => {
-                let mut s_clone = s.redis.lock().await;
-                if miliseconds > now() || miliseconds == 0 {
-                    s_clone.set_after(id, title, miliseconds);
-                } else {
-                    s_clone.del(id);
-                }
-            }
-            "DEL" => {
-                let mut s_clone = s.redis.lock().await;
-                s_clone.del(id);
-            }
-            _ => {
-                eprintln!("Unknown command: {}", command);
-            }
+    // Keys expired since they were logged are removed by the expiry cycle once loaded
+    let deferred = s.redis.lock().await.set_defer_expiry(true);
+    let loaded = if CMD_ARGS.appendonly {
+        load_aof(&s).await
+    } else {
+        Loaded {
+            rewrite: false,
+            replication: load_snapshot(&s).await.and_then(|meta| replication(&meta, 0)),
         }
-    }
+    };
+    s.redis.lock().await.set_defer_expiry(deferred);
+    s.aof_loaded(loaded.rewrite).await;
+    s.resume_replication(loaded.replication).await;
 
     tracing_subscriber::fmt::init();
     volo_gen::volo::redis::ItemServiceServer::new(s)
@@ -67,10 +48,135 @@ async fn main() {
         .unwrap();
 }
 
-fn now() -> u128 {
-    let current_timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Failed to get current timestamp");
+/// How the data was loaded at boot
+struct Loaded {
+    /// The data didn't come from the AOF, which should then be rewritten
+    rewrite: bool,
+    /// Replication id and offset the data is at, known from a snapshot
+    replication: Option<(String, u64)>,
+}
+
+/// Replication id and offset of the data of a snapshot, `after` bytes of the stream later
+fn replication(meta: &snapshot::Meta, after: u64) -> Option<(String, u64)> {
+    (!meta.repl_id.is_empty()).then(|| (meta.repl_id.clone(), meta.repl_offset + after))
+}
+
+/// Load all the data of the snapshot, if any.
+/// return: its metadata, if it was loaded
+async fn load_snapshot(s: &S) -> Option<snapshot::Meta> {
+    let path = snapshot_path();
+    let snap = match snapshot::read(&path) {
+        Ok(Some(snap)) => snap,
+        Ok(None) => return None,
+        Err(e) => {
+            eprintln!("{path}: {e}, starting empty");
+            return None;
+        }
+    };
+    match s.redis.lock().await.restore(snap.databases) {
+        Ok(()) => {
+            println!("Loaded {path}");
+            Some(snap.meta)
+        }
+        Err(e) => {
+            eprintln!("{path}: {e}, starting empty");
+            None
+        }
+    }
+}
+
+/// Replay the AOF, after the snapshot taken at some point of it if any.
+/// A missing or empty AOF leaves the data to the snapshot.
+/// Since the snapshot, the AOF grew by the same records as the replication stream
+async fn load_aof(s: &S) -> Loaded {
+    let path = aof_path();
+    let snap_path = snapshot_path();
+    let aof = match File::open(&path).and_then(|file| Ok((file.metadata()?.len(), file))) {
+        Ok((0, _)) => None,
+        Ok((_, file)) => Some(file),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        }
+    };
+    let Some(mut aof) = aof else {
+        // A new node, or one which ran without AOF
+        let meta = load_snapshot(s).await;
+        if meta.is_none() {
+            println!("No {path} yet, starting empty");
+        }
+        return Loaded {
+            rewrite: meta.is_some(),
+            replication: meta.and_then(|meta| replication(&meta, 0)),
+        };
+    };
+
+    // A snapshot spares replaying the AOF up to where it was taken,
+    // as long as the AOF still starts with the same bytes
+    let mut start = 0;
+    let mut meta = None;
+    match snapshot::read(&snap_path) {
+        Ok(Some(snap)) => {
+            let position = snap.meta.aof_position();
+            let prefix = AofPosition::of(&mut aof, position.offset)
+                .expect("Failed to read AOF file");
+            if prefix != position {
+                eprintln!("{snap_path} doesn't match {path}, replaying all of it instead");
+            } else if let Err(e) = s.redis.lock().await.restore(snap.databases) {
+                eprintln!("{snap_path}: {e}, replaying all of {path} instead");
+            } else {
+                start = position.offset;
+                meta = Some(snap.meta);
+                println!("Loaded {snap_path}, replaying {path} from offset {start}");
+            }
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("{snap_path}: {e}, replaying all of {path} instead"),
+    }
+    aof.seek(SeekFrom::Start(start)).expect("Failed to read AOF file");
+    let mut reader = AofReader::starting_at(BufReader::new(aof), start);
 
-    current_timestamp.as_millis()
+    let (mut replayed, mut skipped) = (0, 0);
+    // The first record which couldn't be replayed, with its offset and why
+    let mut first_skipped = None;
+    let end = loop {
+        let offset = reader.offset();
+        let record = match reader.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => break reader.offset(),
+            Err(AofError::Truncated { offset }) if CMD_ARGS.aof_load_truncated => {
+                eprintln!("{path} ends in an incomplete record, truncating it to {offset} bytes");
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_len(offset))
+                    .expect("Failed to truncate AOF file");
+                break offset;
+            }
+            Err(e @ AofError::Truncated { .. }) => {
+                eprintln!("{path}: {e}, start with `--aof-load-truncated yes` to drop it");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                std::process::exit(1);
+            }
+        };
+        match replay(&mut *s.redis.lock().await, &record) {
+            Ok(_) => replayed += 1,
+            Err(e) => {
+                skipped += 1;
+                first_skipped.get_or_insert((offset, record, e));
+            }
+        }
+    };
+    println!("Replayed {replayed} records of {path}, skipped {skipped}");
+    if let Some((offset, record, e)) = first_skipped {
+        eprintln!("First record skipped, at offset {offset}: {} ({e})", record.join(" "));
+    }
+    Loaded {
+        rewrite: false,
+        replication: meta.and_then(|meta| replication(&meta, end - start)),
+    }
 }
@@ -0,0 +1,391 @@
+//! Offline inspection and repair of AOF and snapshot files, for a server which is not running.
+//! e.g. `vodis-check stats main_server.aof`, `vodis-check truncate main_server.aof`
+use anyhow::{anyhow, bail};
+use clap::Parser;
+use mini_redis::aof::{self, AofError, AofPosition, AofReader};
+use mini_redis::cmdargs::{CheckCommand, CheckConfig};
+use mini_redis::command::{parse_command, replay};
+use mini_redis::redis::{entry_size, Record, Redis, Value};
+use mini_redis::snapshot::{self, Meta};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::BufReader;
+use std::path::Path;
+
+fn main() {
+    let config = CheckConfig::parse();
+    if let Err(e) = run(config.command) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(command: CheckCommand) -> anyhow::Result<()> {
+    match command {
+        CheckCommand::Verify { file, databases } => verify(&file, databases),
+        CheckCommand::Stats {
+            file,
+            separator,
+            top,
+            databases,
+        } => stats(&file, &separator, top, databases),
+        CheckCommand::Truncate { file, dry_run } => truncate(&file, dry_run),
+        CheckCommand::ToSnapshot {
+            aof,
+            snapshot,
+            databases,
+        } => to_snapshot(&aof, &snapshot, databases),
+        CheckCommand::ToAof { snapshot, aof } => to_aof(&snapshot, &aof),
+        CheckCommand::Dump { file } => dump(&file),
+    }
+}
+
+/// Call `f` with the offset and the arguments of each record of the AOF at `path`.
+/// return: the error the reading stopped at, if any
+fn for_each_record(path: &str, mut f: impl FnMut(u64, Record)) -> anyhow::Result<Option<AofError>> {
+    let mut reader = AofReader::new(BufReader::new(File::open(path)?));
+    loop {
+        let offset = reader.offset();
+        match reader.next_record() {
+            Ok(Some(record)) => f(offset, record),
+            Ok(None) => return Ok(None),
+            Err(AofError::Io(e)) => return Err(e.into()),
+            Err(e) => return Ok(Some(e)),
+        }
+    }
+}
+
+/// Replay the AOF at `path` as the server does at boot.
+/// return: the data, and how many times each command was logged
+fn load_aof(path: &str, databases: usize) -> anyhow::Result<(Redis, BTreeMap<String, usize>)> {
+    let mut redis = Redis::new(databases);
+    // Keep the keys expired since, as the server would until its expiry cycle runs
+    redis.set_defer_expiry(true);
+    let mut commands = BTreeMap::new();
+    let error = for_each_record(path, |offset, record| {
+        if let Err(e) = replay(&mut redis, &record) {
+            eprintln!("Invalid command at offset {offset}: {} ({e})", record.join(" "));
+        }
+        if let Some(name) = record.first() {
+            *commands.entry(name.to_uppercase()).or_insert(0) += 1;
+        }
+    })?;
+    if let Some(e) = error {
+        bail!("{path}: {e}, `vodis-check truncate {path}` drops it and what follows");
+    }
+    Ok((redis, commands))
+}
+
+fn load_snapshot(path: &str) -> anyhow::Result<snapshot::Snapshot> {
+    snapshot::read(path)
+        .map_err(|e| anyhow!("{path}: {e}"))?
+        .ok_or_else(|| anyhow!("{path}: no such file"))
+}
+
+fn verify(file: &str, databases: usize) -> anyhow::Result<()> {
+    if snapshot::is_snapshot(file)? {
+        let snap = load_snapshot(file)?;
+        let keys: usize = snap.databases.iter().map(Vec::len).sum();
+        println!(
+            "{file}: snapshot OK, {keys} keys in {} databases, taken at AOF offset {}",
+            snap.databases.len(),
+            snap.meta.aof_offset
+        );
+        return Ok(());
+    }
+    let mut redis = Redis::new(databases);
+    redis.set_defer_expiry(true);
+    let (mut records, mut invalid) = (0, 0);
+    let error = for_each_record(file, |offset, record| {
+        records += 1;
+        let known = record.first().is_some_and(|name| parse_command(name).is_some());
+        if !known {
+            println!("Unknown command at offset {offset}: {}", record.join(" "));
+            invalid += 1;
+        } else if let Err(e) = replay(&mut redis, &record) {
+            println!("Invalid command at offset {offset}: {} ({e})", record.join(" "));
+            invalid += 1;
+        }
+    })?;
+    match error {
+        Some(e) => bail!("{file}: {e}, after {records} readable records"),
+        None if invalid > 0 => bail!("{file}: {records} records, {invalid} of them invalid"),
+        None => {
+            println!("{file}: AOF OK, {records} records");
+            Ok(())
+        }
+    }
+}
+
+fn stats(file: &str, separator: &str, top: usize, databases: usize) -> anyhow::Result<()> {
+    let (data, commands) = if snapshot::is_snapshot(file)? {
+        let snap = load_snapshot(file)?;
+        println!("snapshot taken at {} (unix time), by node {:?}", snap.meta.created_at, snap.meta.repl_id);
+        (snap.databases, None)
+    } else {
+        let (redis, commands) = load_aof(file, databases)?;
+        (redis.snapshot().into_databases(), Some(commands))
+    };
+
+    let keys: usize = data.iter().map(Vec::len).sum();
+    let per_db: Vec<String> = data
+        .iter()
+        .enumerate()
+        .filter(|(_, entries)| !entries.is_empty())
+        .map(|(db, entries)| format!("db{db}: {}", entries.len()))
+        .collect();
+    println!("keys: {keys} ({})", per_db.join(", "));
+
+    let mut types: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut expiring = 0;
+    // Per prefix: keys and estimated bytes
+    let mut prefixes: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (key, value, expired_at) in data.iter().flatten() {
+        *types.entry(value.type_name()).or_insert(0) += 1;
+        expiring += expired_at.is_some() as usize;
+        let prefix = key.split_once(separator).map_or(key.as_str(), |(prefix, _)| prefix);
+        let (count, size) = prefixes.entry(prefix).or_insert((0, 0));
+        *count += 1;
+        *size += entry_size(key, value);
+    }
+    let types: Vec<String> = types.iter().map(|(name, n)| format!("{name}: {n}")).collect();
+    println!("types: {}", types.join(", "));
+    println!("with an expiry: {expiring}");
+
+    let mut prefixes: Vec<_> = prefixes.into_iter().collect();
+    prefixes.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(b.0)));
+    println!("size by prefix, estimated bytes ({} prefixes):", prefixes.len());
+    for (prefix, (count, size)) in prefixes.iter().take(top) {
+        println!("  {prefix:<24} {size:>12}  {count} keys");
+    }
+
+    if let Some(commands) = commands {
+        let mut commands: Vec<_> = commands.into_iter().collect();
+        commands.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        println!("commands:");
+        for (name, count) in commands {
+            println!("  {name:<24} {count:>12}");
+        }
+    }
+    Ok(())
+}
+
+fn truncate(file: &str, dry_run: bool) -> anyhow::Result<()> {
+    if snapshot::is_snapshot(file)? {
+        bail!("{file}: a snapshot, only AOFs can be truncated");
+    }
+    let len = File::open(file)?.metadata()?.len();
+    let offset = match for_each_record(file, |_, _| {})? {
+        None => {
+            println!("{file}: nothing to truncate");
+            return Ok(());
+        }
+        Some(AofError::Truncated { offset }) | Some(AofError::Corrupt { offset, .. }) => offset,
+        Some(e @ AofError::Io(_)) => return Err(e.into()),
+    };
+    if dry_run {
+        println!("{file}: would drop {} bytes from offset {offset}", len - offset);
+        return Ok(());
+    }
+    OpenOptions::new().write(true).open(file)?.set_len(offset)?;
+    println!("{file}: dropped {} bytes from offset {offset}", len - offset);
+    Ok(())
+}
+
+fn to_snapshot(aof: &str, path: &str, databases: usize) -> anyhow::Result<()> {
+    let (redis, _) = load_aof(aof, databases)?;
+    // The whole AOF is in the snapshot, so the server loading both replays none of it
+    let position = AofPosition::of(File::open(aof)?, u64::MAX)?;
+    let meta = Meta {
+        created_at: (Redis::now() / 1000) as u64,
+        aof_offset: position.offset,
+        aof_digest: position.digest,
+        ..Default::default()
+    };
+    snapshot::write(path, redis.snapshot().into_databases(), &meta)?;
+    println!("{path}: written from {aof}");
+    Ok(())
+}
+
+fn to_aof(path: &str, aof: &str) -> anyhow::Result<()> {
+    if Path::new(aof).exists() {
+        bail!("{aof}: already exists, not overwritten");
+    }
+    let snap = load_snapshot(path)?;
+    let mut redis = Redis::new(snap.databases.len());
+    redis.restore(snap.databases)?;
+    aof::write_rewrite(aof, redis.snapshot().records())?;
+    println!("{aof}: written from {path}");
+    Ok(())
+}
+
+fn dump(file: &str) -> anyhow::Result<()> {
+    if snapshot::is_snapshot(file)? {
+        let snap = load_snapshot(file)?;
+        println!("{}", json!({ "meta": snap.meta }));
+        for (db, entries) in snap.databases.iter().enumerate() {
+            for (key, value, expired_at) in entries {
+                let entry = json!({
+                    "db": db,
+                    "key": key,
+                    "type": value.type_name(),
+                    "value": value_json(value),
+                    "expires_at": expired_at.map(|at| at as u64),
+                });
+                println!("{entry}");
+            }
+        }
+        return Ok(());
+    }
+    let error = for_each_record(file, |offset, record| {
+        println!("{}", json!({ "offset": offset, "command": record }));
+    })?;
+    match error {
+        Some(e) => Err(anyhow!("{file}: {e}")),
+        None => Ok(()),
+    }
+}
+
+fn value_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Str(s) => json!(s),
+        Value::List(list) => json!(list),
+        Value::Hash(hash) => json!(hash),
+        Value::Set(set) => json!(set),
+        Value::ZSet(zset) => json!(zset.range_by_rank(0, zset.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for the files of a test, removed when dropped
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("vodis-check-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.0.join(file).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn records(records: &[&[&str]]) -> String {
+        records
+            .iter()
+            .map(|record| {
+                aof::encode(&record.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+            })
+            .collect()
+    }
+
+    /// The entries of each database as text, in key order
+    fn contents(redis: &Redis) -> Vec<Vec<String>> {
+        redis
+            .snapshot()
+            .into_databases()
+            .into_iter()
+            .map(|entries| {
+                let mut entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value, expired_at)| {
+                        let value = match value {
+                            // In no particular order
+                            Value::Set(set) => {
+                                let mut members: Vec<_> = set.iter().collect();
+                                members.sort();
+                                json!(members)
+                            }
+                            value => value_json(value),
+                        };
+                        format!("{key} {value} {expired_at:?}")
+                    })
+                    .collect();
+                entries.sort();
+                entries
+            })
+            .collect()
+    }
+
+    #[test]
+    fn truncate_drops_the_bad_tail() {
+        let dir = TempDir::new("truncate");
+        let file = dir.path("a.aof");
+        let good = records(&[&["SET", "a", "1"], &["SET", "b", "2"]]);
+        let tail = records(&[&["SET", "c", "3"]]);
+        for bad in [&tail[..tail.len() - 3], "*1\r\n$x\r\n"] {
+            std::fs::write(&file, format!("{good}{bad}")).unwrap();
+            truncate(&file, true).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(&file).unwrap(),
+                format!("{good}{bad}")
+            );
+            truncate(&file, false).unwrap();
+            assert_eq!(std::fs::read_to_string(&file).unwrap(), good);
+            // Nothing left to drop
+            truncate(&file, false).unwrap();
+            assert_eq!(std::fs::read_to_string(&file).unwrap(), good);
+        }
+    }
+
+    #[test]
+    fn truncate_refuses_snapshots() {
+        let dir = TempDir::new("truncate-snapshot");
+        let file = dir.path("a.snap");
+        snapshot::write(&file, vec![], &Meta::default()).unwrap();
+        let before = std::fs::read(&file).unwrap();
+        assert!(truncate(&file, false).is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), before);
+    }
+
+    #[test]
+    fn aof_to_snapshot_and_back() {
+        let dir = TempDir::new("round-trip");
+        let (aof, snap, back) = (dir.path("a.aof"), dir.path("a.snap"), dir.path("b.aof"));
+        let later = (Redis::now() + 3_600_000).to_string();
+        let log = records(&[
+            &["SET", "s", "x"],
+            &["SET", "t", "y", "PXAT", &later],
+            &["RPUSH", "l", "a", "b"],
+            &["HSET", "h", "f", "v"],
+            &["SADD", "set", "m", "n"],
+            &["ZADD", "z", "1.5", "m"],
+            &["DEL", "s"],
+            &["SELECT", "2"],
+            &["SET", "other", "db2"],
+            &["SELECT", "0"],
+        ]);
+        std::fs::write(&aof, &log).unwrap();
+        to_snapshot(&aof, &snap, 4).unwrap();
+        let snapshot = load_snapshot(&snap).unwrap();
+        // The snapshot stands for the whole AOF
+        assert_eq!(snapshot.meta.aof_offset, log.len() as u64);
+        assert_eq!(
+            snapshot.meta.aof_position(),
+            AofPosition::of(log.as_bytes(), u64::MAX).unwrap()
+        );
+        to_aof(&snap, &back).unwrap();
+        let (original, _) = load_aof(&aof, 4).unwrap();
+        let (converted, _) = load_aof(&back, 4).unwrap();
+        let expected = contents(&original);
+        assert_eq!(contents(&converted), expected);
+        assert_eq!(expected[0].len(), 5);
+        assert_eq!(expected[2].len(), 1);
+        // An existing AOF is not overwritten
+        assert!(to_aof(&snap, &aof).is_err());
+        assert_eq!(std::fs::read_to_string(&aof).unwrap(), log);
+    }
+}
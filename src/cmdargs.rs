@@ -3,15 +3,25 @@ use clap::Parser;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct ServerConfig {
-    /// Server name, used in default AOF file name.
-    /// Will be randomly chosen if not provided
+    /// Server name, used in the default AOF and snapshot file names.
+    /// `server` if not provided
     #[arg(short, long)]
     pub name: Option<String>,
 
-    /// Optional AOF file path
+    /// Optional AOF file path, `<name>.aof` by default
     #[arg(short, long, value_name = "FILE")]
     pub aof: Option<String>,
 
+    /// Log the writes to the AOF and replay it at boot.
+    /// With `no`, the data is only kept in snapshots, and loaded from the snapshot at boot
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub appendonly: bool,
+
     /// Mark this Vodis instance as a slave
     #[arg(short, long, value_name = "Master IP:PORT")]
     pub slaveof: Option<String>,
@@ -29,6 +39,126 @@ pub struct ServerConfig {
     /// Execute provided commands after initialization
     #[arg(long)]
     pub pre_run: Option<Vec<String>>,
+
+    /// Number of databases, selected with SELECT or the `db` of a request
+    #[arg(long, default_value_t = 16)]
+    pub databases: usize,
+
+    /// Memory limit of the data, e.g. `100mb`; 0 for no limit
+    #[arg(long, default_value = "0", value_parser = parse_memory)]
+    pub maxmemory: usize,
+
+    /// Which keys to evict once `maxmemory` is reached
+    #[arg(long, value_enum, default_value_t = EvictionPolicy::NoEviction)]
+    pub maxmemory_policy: EvictionPolicy,
+
+    /// Keys sampled to pick one to evict, the more the more accurate and the slower
+    #[arg(long, default_value_t = 5)]
+    pub maxmemory_samples: usize,
+
+    /// Load an AOF ending in an incomplete record, as left by a crash, by truncating that record.
+    /// With `no`, the server refuses to start instead
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub aof_load_truncated: bool,
+
+    /// Rewrite the AOF once it grew by this percentage since the last rewrite, 0 to never
+    #[arg(long, default_value_t = 100)]
+    pub auto_aof_rewrite_percentage: u64,
+
+    /// Size under which the AOF is not rewritten automatically, e.g. `64mb`
+    #[arg(long, default_value = "64mb", value_parser = parse_memory)]
+    pub auto_aof_rewrite_min_size: usize,
+
+    /// When the AOF is synced to disk; under `always` a write is acknowledged once it is synced
+    #[arg(long, value_enum, default_value_t = AppendFsync::Everysec)]
+    pub appendfsync: AppendFsync,
+
+    /// Save a snapshot once SECONDS passed and CHANGES writes were made since the last one,
+    /// may be given several times
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["SECONDS", "CHANGES"],
+        action = clap::ArgAction::Append
+    )]
+    pub save: Vec<u64>,
+
+    /// Last writes kept for the replicas which reconnect, e.g. `1mb`.
+    /// A replica further behind is sent all the data again
+    #[arg(long, default_value = "1mb", value_parser = parse_memory)]
+    pub repl_backlog_size: usize,
+
+    /// Stream queued for a replica beyond which it is disconnected, e.g. `256mb`; 0 for no limit.
+    /// Writes never wait for the replicas
+    #[arg(long, default_value = "256mb", value_parser = parse_memory)]
+    pub replica_output_limit: usize,
+
+    /// Stream queued for a replica beyond which it is disconnected after
+    /// `--replica-output-soft-seconds` seconds; 0 for no limit
+    #[arg(long, default_value = "64mb", value_parser = parse_memory)]
+    pub replica_output_soft_limit: usize,
+
+    #[arg(long, default_value_t = 60)]
+    pub replica_output_soft_seconds: u64,
+
+    /// Seconds without news from the master after which a replica syncs again.
+    /// An idle master pings its replicas every 10 seconds, so this should be longer
+    #[arg(long, default_value_t = 60)]
+    pub repl_timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AppendFsync {
+    /// After every batch of writes, before they are replied to
+    Always,
+    /// Once a second, up to a second of writes may be lost
+    Everysec,
+    /// Never, left to the operating system
+    No,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EvictionPolicy {
+    /// Reject the writes
+    #[value(name = "noeviction")]
+    NoEviction,
+    /// Least recently used keys
+    AllkeysLru,
+    /// Least frequently used keys
+    AllkeysLfu,
+    /// Least recently used keys among the ones with an expiry
+    VolatileLru,
+    /// Keys with the nearest expiry
+    VolatileTtl,
+    /// Random keys
+    AllkeysRandom,
+}
+
+/// Bytes, with an optional unit as in redis.conf: `k`/`m`/`g` are powers of 1000,
+/// `kb`/`mb`/`gb` powers of 1024
+fn parse_memory(arg: &str) -> Result<usize, String> {
+    let arg = arg.to_lowercase();
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(split);
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory unit `{unit}`")),
+    };
+    let number: usize = number.parse().map_err(|e| format!("{e}"))?;
+    number
+        .checked_mul(unit)
+        .ok_or_else(|| "memory size too large".to_string())
 }
 
 #[derive(Parser)]
@@ -59,4 +189,66 @@ pub struct ProxyConfig {
     /// If specified in config file, this can be omitted
     #[arg(long)]
     pub masters: Option<Vec<String>>,
+
+    /// Hash only the `{...}` tag of a key that has one, so that keys sharing a tag share a slot.
+    /// Keys holding a tag then move to another slot: turn it on for an empty cluster only
+    #[arg(
+        long,
+        default_value_t = false,
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub hash_tags: bool,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CheckConfig {
+    #[command(subcommand)]
+    pub command: CheckCommand,
+}
+
+/// Offline inspection and repair of AOF and snapshot files.
+/// The kind of a file is told by its first bytes
+#[derive(clap::Subcommand)]
+pub enum CheckCommand {
+    /// Check that every record of an AOF, or every section of a snapshot, can be read
+    Verify {
+        file: String,
+        /// Number of databases the AOF may SELECT
+        #[arg(long, default_value_t = 16)]
+        databases: usize,
+    },
+    /// Key counts, size by key prefix, and for an AOF a histogram of the commands
+    Stats {
+        file: String,
+        /// Keys are grouped by what comes before the first separator
+        #[arg(long, default_value = ":")]
+        separator: String,
+        /// Prefixes shown, the largest first
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        /// Number of databases the AOF may SELECT
+        #[arg(long, default_value_t = 16)]
+        databases: usize,
+    },
+    /// Cut an AOF at its first incomplete or bad record, dropping everything from it
+    Truncate {
+        file: String,
+        /// Only tell what would be dropped
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Replay an AOF and save the data as a snapshot, matching that AOF
+    ToSnapshot {
+        aof: String,
+        snapshot: String,
+        /// Number of databases the AOF may SELECT
+        #[arg(long, default_value_t = 16)]
+        databases: usize,
+    },
+    /// Write the data of a snapshot as the fewest AOF records
+    ToAof { snapshot: String, aof: String },
+    /// Print the records of an AOF, or the entries of a snapshot, as JSON lines
+    Dump { file: String },
 }
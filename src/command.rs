@@ -0,0 +1,1202 @@
+//! Data-type commands: argument parsing and execution against `Redis`.
+//!
+//! Every write that actually changes the dataset is recorded through
+//! `Redis::propagate`, so that the caller can append it to the AOF and
+//! forward it to slaves.
+
+use crate::redis::{Record, Redis, SetOp, Timestamp};
+use crate::zset::ZAddFlags;
+use anyhow::{anyhow, Result};
+use pilota::FastStr;
+use std::ops::Bound;
+use volo_gen::volo::redis::{GetItemResponse, RedisCommand};
+
+/// Reply of a command, converted to `GetItemResponse` when sent back
+#[derive(Debug)]
+pub enum Reply {
+    Nil,
+    Int(i64),
+    Str(String),
+    Array(Vec<Reply>),
+}
+
+impl From<Reply> for GetItemResponse {
+    fn from(reply: Reply) -> Self {
+        match reply {
+            Reply::Nil => GetItemResponse {
+                ok: false,
+                data: None,
+                items: None,
+            },
+            Reply::Int(i) => GetItemResponse {
+                ok: true,
+                data: Some(i.to_string().into()),
+                items: None,
+            },
+            Reply::Str(s) => GetItemResponse {
+                ok: true,
+                data: Some(s.into()),
+                items: None,
+            },
+            Reply::Array(replies) => GetItemResponse {
+                ok: true,
+                data: None,
+                items: Some(replies.into_iter().map(Into::into).collect()),
+            },
+        }
+    }
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Str("OK".into())
+    }
+
+    fn strings(items: Vec<String>) -> Self {
+        Reply::Array(items.into_iter().map(Reply::Str).collect())
+    }
+}
+
+/// Upper-case name of a command, as written in AOF records
+pub fn command_name(cmd: RedisCommand) -> String {
+    format!("{:?}", cmd).to_uppercase()
+}
+
+/// Look a command up by its case-insensitive name
+pub fn parse_command(name: &str) -> Option<RedisCommand> {
+    (0..)
+        .map_while(|i| RedisCommand::try_from(i).ok())
+        .find(|cmd| command_name(*cmd).eq_ignore_ascii_case(name))
+}
+
+/// Whether the command may modify the dataset
+pub fn is_write(cmd: RedisCommand) -> bool {
+    matches!(
+        cmd,
+        RedisCommand::Set
+            | RedisCommand::Del
+            | RedisCommand::Incr
+            | RedisCommand::Decr
+            | RedisCommand::IncrBy
+            | RedisCommand::DecrBy
+            | RedisCommand::IncrByFloat
+            | RedisCommand::MSet
+            | RedisCommand::MSetNx
+            | RedisCommand::Expire
+            | RedisCommand::PExpire
+            | RedisCommand::ExpireAt
+            | RedisCommand::PExpireAt
+            | RedisCommand::Persist
+            | RedisCommand::Rename
+            | RedisCommand::RenameNx
+            | RedisCommand::Copy
+            | RedisCommand::Move
+            | RedisCommand::SwapDb
+            | RedisCommand::FlushDb
+            | RedisCommand::FlushAll
+            | RedisCommand::Append
+            | RedisCommand::SetRange
+            | RedisCommand::GetSet
+            | RedisCommand::GetDel
+            | RedisCommand::GetEx
+            | RedisCommand::LPush
+            | RedisCommand::RPush
+            | RedisCommand::LPop
+            | RedisCommand::RPop
+            | RedisCommand::LSet
+            | RedisCommand::LRem
+            | RedisCommand::LTrim
+            | RedisCommand::HSet
+            | RedisCommand::HDel
+            | RedisCommand::HIncrBy
+            | RedisCommand::SAdd
+            | RedisCommand::SRem
+            | RedisCommand::SPop
+            | RedisCommand::SInterStore
+            | RedisCommand::SUnionStore
+            | RedisCommand::SDiffStore
+            | RedisCommand::ZAdd
+            | RedisCommand::ZRem
+            | RedisCommand::ZIncrBy
+            | RedisCommand::ZPopMin
+            | RedisCommand::ZPopMax
+            | RedisCommand::ZRemRangeByScore
+    )
+}
+
+/// Whether the command may use more memory, and so is rejected or evicts keys
+/// once `maxmemory` is reached
+pub fn may_grow(cmd: RedisCommand) -> bool {
+    matches!(
+        cmd,
+        RedisCommand::Set
+            | RedisCommand::Incr
+            | RedisCommand::Decr
+            | RedisCommand::IncrBy
+            | RedisCommand::DecrBy
+            | RedisCommand::IncrByFloat
+            | RedisCommand::MSet
+            | RedisCommand::MSetNx
+            | RedisCommand::Copy
+            | RedisCommand::Append
+            | RedisCommand::SetRange
+            | RedisCommand::GetSet
+            | RedisCommand::LPush
+            | RedisCommand::RPush
+            | RedisCommand::LSet
+            | RedisCommand::HSet
+            | RedisCommand::HIncrBy
+            | RedisCommand::SAdd
+            | RedisCommand::SInterStore
+            | RedisCommand::SUnionStore
+            | RedisCommand::SDiffStore
+            | RedisCommand::ZAdd
+            | RedisCommand::ZIncrBy
+    )
+}
+
+/// The keys a command operates on, used by the proxy to route it
+pub fn command_keys<A: AsRef<str>>(cmd: RedisCommand, args: &[A]) -> Vec<&str> {
+    match cmd {
+        RedisCommand::Ping
+        | RedisCommand::Publish
+        | RedisCommand::Subscribe
+        | RedisCommand::Replicaof
+        | RedisCommand::Sync
+        | RedisCommand::ClusterCreate
+        | RedisCommand::ClusterMeet
+        | RedisCommand::ClusterAddSlots
+        | RedisCommand::Fetch
+        | RedisCommand::ReplStream
+        | RedisCommand::Multi
+        | RedisCommand::Exec
+        | RedisCommand::DbSize
+        | RedisCommand::RandomKey
+        | RedisCommand::Keys
+        | RedisCommand::Scan
+        | RedisCommand::Select
+        | RedisCommand::SwapDb
+        | RedisCommand::FlushDb
+        | RedisCommand::FlushAll
+        | RedisCommand::BgRewriteAof
+        | RedisCommand::Save
+        | RedisCommand::BgSave
+        | RedisCommand::LastSave => vec![],
+        RedisCommand::Rename | RedisCommand::RenameNx | RedisCommand::Copy => {
+            args.iter().take(2).map(|arg| arg.as_ref()).collect()
+        }
+        RedisCommand::MSet | RedisCommand::MSetNx => {
+            args.iter().step_by(2).map(|arg| arg.as_ref()).collect()
+        }
+        RedisCommand::Del
+        | RedisCommand::Exists
+        | RedisCommand::MGet
+        | RedisCommand::SInter
+        | RedisCommand::SUnion
+        | RedisCommand::SDiff
+        | RedisCommand::SInterStore
+        | RedisCommand::SUnionStore
+        | RedisCommand::SDiffStore => args.iter().map(|arg| arg.as_ref()).collect(),
+        _ => args.iter().take(1).map(|arg| arg.as_ref()).collect(),
+    }
+}
+
+fn check_args(cmd: RedisCommand, args: &[FastStr], min: usize, max: Option<usize>) -> Result<()> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        return Err(anyhow!(
+            "Invalid arguments count: {} for `{}`",
+            args.len(),
+            command_name(cmd)
+        ));
+    }
+    Ok(())
+}
+
+fn parse_int(arg: &str) -> Result<i64> {
+    arg.parse()
+        .map_err(|_| anyhow!("ERR value is not an integer or out of range"))
+}
+
+fn parse_count(arg: &str) -> Result<usize> {
+    arg.parse()
+        .map_err(|_| anyhow!("ERR value is out of range, must be positive"))
+}
+
+fn parse_db(redis: &Redis, arg: &str) -> Result<usize> {
+    let db = arg
+        .parse::<usize>()
+        .map_err(|_| anyhow!("ERR DB index is out of range"))?;
+    redis.check_db(db)?;
+    Ok(db)
+}
+
+/// `ASYNC` frees the memory in the background, `SYNC` (the default) right away
+fn parse_flush_mode(cmd: RedisCommand, args: &[FastStr]) -> Result<bool> {
+    check_args(cmd, args, 0, Some(1))?;
+    match args.first().map(|arg| arg.to_uppercase()).as_deref() {
+        None | Some("SYNC") => Ok(false),
+        Some("ASYNC") => Ok(true),
+        _ => Err(anyhow!("ERR syntax error")),
+    }
+}
+
+/// Largest string SETRANGE may produce, as in Redis (512MB)
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+fn parse_float(arg: &str) -> Result<f64> {
+    match arg.parse::<f64>() {
+        std::result::Result::Ok(f) if !f.is_nan() => Ok(f),
+        _ => Err(anyhow!("ERR value is not a valid float")),
+    }
+}
+
+/// `1.5` is inclusive, `(1.5` exclusive, `-inf` / `+inf` for no bound
+fn parse_score_bound(arg: &str) -> Result<Bound<f64>> {
+    match arg.strip_prefix('(') {
+        Some(score) => Ok(Bound::Excluded(parse_float(score)?)),
+        None => Ok(Bound::Included(parse_float(arg)?)),
+    }
+}
+
+/// `[a` is inclusive, `(a` exclusive, `-` / `+` for the lowest / highest bound.
+/// None if the bound excludes everything, i.e. `+` as the min or `-` as the max
+fn parse_lex_bound(arg: &str, is_min: bool) -> Result<Option<Bound<&str>>> {
+    match (arg, is_min) {
+        ("-", true) | ("+", false) => Ok(Some(Bound::Unbounded)),
+        ("+", true) | ("-", false) => Ok(None),
+        _ => match (arg.strip_prefix('['), arg.strip_prefix('(')) {
+            (Some(member), _) => Ok(Some(Bound::Included(member))),
+            (_, Some(member)) => Ok(Some(Bound::Excluded(member))),
+            _ => Err(anyhow!("ERR min or max not valid string range item")),
+        },
+    }
+}
+
+fn score_reply(score: f64) -> Reply {
+    Reply::Str(score.to_string())
+}
+
+fn pairs_reply(pairs: Vec<(String, f64)>, with_scores: bool) -> Reply {
+    Reply::Array(
+        pairs
+            .into_iter()
+            .flat_map(|(member, score)| {
+                let score = with_scores.then(|| score_reply(score));
+                std::iter::once(Reply::Str(member)).chain(score)
+            })
+            .collect(),
+    )
+}
+
+/// ZRANGE and ZRANGEBYSCORE
+fn zrange(
+    redis: &mut Redis,
+    key: &str,
+    (start, stop): (&str, &str),
+    options: &[FastStr],
+    by_score: bool,
+) -> Result<Reply> {
+    let (mut by_score, mut by_lex, mut rev, mut with_scores) = (by_score, false, false, false);
+    let mut limit: Option<(usize, i64)> = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "BYLEX" => by_lex = true,
+            "REV" => rev = true,
+            "WITHSCORES" => with_scores = true,
+            "LIMIT" => match (options.next(), options.next()) {
+                (Some(offset), Some(count)) => {
+                    limit = Some((parse_count(offset)?, parse_int(count)?));
+                }
+                _ => return Err(anyhow!("ERR syntax error")),
+            },
+            _ => return Err(anyhow!("ERR syntax error")),
+        }
+    }
+    if by_score && by_lex {
+        return Err(anyhow!("ERR syntax error"));
+    }
+    if limit.is_some() && !(by_score || by_lex) {
+        return Err(anyhow!(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+        ));
+    }
+    if by_lex && with_scores {
+        return Err(anyhow!(
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX"
+        ));
+    }
+    // With REV, score and lex ranges are given from max to min
+    let (min, max) = if rev && (by_score || by_lex) {
+        (stop, start)
+    } else {
+        (start, stop)
+    };
+    let Some(zset) = redis.zset(key)? else {
+        return Ok(Reply::Array(vec![]));
+    };
+    let len = zset.len();
+    let (mut first, mut end) = if by_score {
+        zset.ranks_by_score(parse_score_bound(min)?, parse_score_bound(max)?)
+    } else if by_lex {
+        match (parse_lex_bound(min, true)?, parse_lex_bound(max, false)?) {
+            (Some(min), Some(max)) => zset.ranks_by_lex(min, max),
+            _ => (0, 0),
+        }
+    } else {
+        match Redis::range(parse_int(min)?, parse_int(max)?, len) {
+            // Ranks of REV count from the highest
+            Some((a, b)) if rev => (len - 1 - b, len - a),
+            Some((a, b)) => (a, b + 1),
+            None => (0, 0),
+        }
+    };
+    if let Some((offset, count)) = limit {
+        let count = if count < 0 {
+            usize::MAX
+        } else {
+            count as usize
+        };
+        if rev {
+            end = end.saturating_sub(offset).max(first);
+            first = first.max(end.saturating_sub(count));
+        } else {
+            first = (first + offset).min(end);
+            end = end.min(first.saturating_add(count));
+        }
+    }
+    let mut pairs = zset.range_by_rank(first, end);
+    if rev {
+        pairs.reverse();
+    }
+    Ok(pairs_reply(pairs, with_scores))
+}
+
+/// What SET does to the expiry of the key
+#[derive(Debug, Default)]
+pub enum SetExpiry {
+    /// Never expire
+    #[default]
+    Clear,
+    /// Keep the expiry the key had
+    Keep,
+    At(Timestamp),
+}
+
+/// Options of SET, after the key and value
+#[derive(Debug, Default)]
+pub struct SetOptions {
+    /// Only set if the key does not exist
+    pub nx: bool,
+    /// Only set if the key exists
+    pub xx: bool,
+    /// Reply with the old value
+    pub get: bool,
+    pub expiry: SetExpiry,
+}
+
+/// Parse `NX`, `XX`, `GET`, `KEEPTTL`, `EX`, `PX`, `EXAT` and `PXAT`, in any order
+pub fn parse_set_options(options: &[FastStr]) -> Result<SetOptions> {
+    let mut parsed = SetOptions::default();
+    let mut has_expiry = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let option = option.to_uppercase();
+        match option.as_str() {
+            "NX" if !parsed.xx => parsed.nx = true,
+            "XX" if !parsed.nx => parsed.xx = true,
+            "GET" => parsed.get = true,
+            "KEEPTTL" if !has_expiry => {
+                parsed.expiry = SetExpiry::Keep;
+                has_expiry = true;
+            }
+            "EX" | "PX" | "EXAT" | "PXAT" if !has_expiry => {
+                let time = options.next().ok_or(anyhow!("ERR syntax error"))?;
+                let time = parse_int(time)?;
+                let time = u128::try_from(time)
+                    .ok()
+                    .filter(|time| *time > 0)
+                    .ok_or(anyhow!("ERR invalid expire time in 'set' command"))?;
+                parsed.expiry = SetExpiry::At(match option.as_str() {
+                    "EX" => Redis::now() + time * 1000,
+                    "PX" => Redis::now() + time,
+                    "EXAT" => time * 1000,
+                    _ => time,
+                });
+                has_expiry = true;
+            }
+            _ => return Err(anyhow!("ERR syntax error")),
+        }
+    }
+    Ok(parsed)
+}
+
+/// SET with its options. The write is recorded with its expiry as an absolute deadline.
+/// return: the reply, and whether the key was written
+pub fn set(
+    redis: &mut Redis,
+    key: &str,
+    value: &str,
+    options: &SetOptions,
+) -> Result<(Reply, bool)> {
+    redis.free_memory()?;
+    // With GET, a key of another type is an error, even if the write is skipped
+    let old = if options.get { redis.get(key)? } else { None };
+    let exists = redis.exists(key);
+    if (options.nx && exists) || (options.xx && !exists) {
+        let reply = old.map_or(Reply::Nil, Reply::Str);
+        return Ok((reply, false));
+    }
+    let expired_at = match options.expiry {
+        SetExpiry::Clear => 0,
+        SetExpiry::Keep => redis.expiry(key).unwrap_or(0),
+        SetExpiry::At(at) => at,
+    };
+    redis.set_at(key, value, expired_at);
+    let mut record = vec![
+        command_name(RedisCommand::Set),
+        key.to_string(),
+        value.to_string(),
+    ];
+    if expired_at != 0 {
+        record.extend(["PXAT".to_string(), expired_at.to_string()]);
+    }
+    redis.propagate(record);
+    let reply = match options.get {
+        true => old.map_or(Reply::Nil, Reply::Str),
+        false => Reply::ok(),
+    };
+    Ok((reply, true))
+}
+
+fn record(cmd: RedisCommand, args: &[FastStr]) -> Record {
+    std::iter::once(command_name(cmd))
+        .chain(args.iter().map(|arg| arg.to_string()))
+        .collect()
+}
+
+/// Execute a data-type command
+pub fn execute(redis: &mut Redis, cmd: RedisCommand, args: &[FastStr]) -> Result<Reply> {
+    if may_grow(cmd) {
+        redis.free_memory()?;
+    }
+    let reply = match cmd {
+        RedisCommand::Set => {
+            check_args(cmd, args, 2, None)?;
+            let options = parse_set_options(&args[2..])?;
+            set(redis, &args[0], &args[1], &options)?.0
+        }
+        RedisCommand::Incr | RedisCommand::Decr => {
+            check_args(cmd, args, 1, Some(1))?;
+            let delta = if cmd == RedisCommand::Incr { 1 } else { -1 };
+            let value = redis.incr_by(&args[0], delta)?;
+            redis.propagate(record(cmd, args));
+            Reply::Int(value)
+        }
+        RedisCommand::IncrBy | RedisCommand::DecrBy => {
+            check_args(cmd, args, 2, Some(2))?;
+            let delta = parse_int(&args[1])?;
+            let delta = if cmd == RedisCommand::IncrBy {
+                Some(delta)
+            } else {
+                delta.checked_neg()
+            }
+            .ok_or(anyhow!("ERR decrement would overflow"))?;
+            let value = redis.incr_by(&args[0], delta)?;
+            redis.propagate(record(cmd, args));
+            Reply::Int(value)
+        }
+        RedisCommand::IncrByFloat => {
+            check_args(cmd, args, 2, Some(2))?;
+            let delta = parse_float(&args[1])?;
+            let value = redis.incr_by_float(&args[0], delta)?;
+            // The result, as floats may be added up differently elsewhere
+            let set = ["SET", &args[0], &value, "KEEPTTL"];
+            redis.propagate(set.iter().map(|arg| arg.to_string()).collect());
+            Reply::Str(value)
+        }
+        RedisCommand::MGet => {
+            check_args(cmd, args, 1, None)?;
+            // A key holding another type reads as nil, not as an error
+            let values = args.iter().map(|key| redis.get(key).ok().flatten());
+            Reply::Array(values.map(|v| v.map_or(Reply::Nil, Reply::Str)).collect())
+        }
+        RedisCommand::MSet | RedisCommand::MSetNx => {
+            check_args(cmd, args, 2, None)?;
+            if !args.len().is_multiple_of(2) {
+                return Err(anyhow!(
+                    "ERR wrong number of arguments for '{}' command",
+                    command_name(cmd).to_lowercase()
+                ));
+            }
+            // MSETNX sets all the keys or none of them
+            if cmd == RedisCommand::MSetNx && args.iter().step_by(2).any(|key| redis.exists(key)) {
+                return Ok(Reply::Int(0));
+            }
+            for pair in args.chunks(2) {
+                redis.set_at(&pair[0], &pair[1], 0);
+            }
+            redis.propagate(record(cmd, args));
+            match cmd {
+                RedisCommand::MSet => Reply::ok(),
+                _ => Reply::Int(1),
+            }
+        }
+        RedisCommand::Exists => {
+            check_args(cmd, args, 1, None)?;
+            // A key given twice is counted twice
+            Reply::Int(args.iter().filter(|key| redis.exists(key)).count() as i64)
+        }
+        RedisCommand::Type => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::Str(redis.key_type(&args[0]).unwrap_or("none").to_string())
+        }
+        RedisCommand::DbSize => {
+            check_args(cmd, args, 0, Some(0))?;
+            Reply::Int(redis.dbsize() as i64)
+        }
+        RedisCommand::RandomKey => {
+            check_args(cmd, args, 0, Some(0))?;
+            redis.random_key().map_or(Reply::Nil, Reply::Str)
+        }
+        RedisCommand::Keys => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::strings(redis.keys(&args[0]))
+        }
+        RedisCommand::Scan => {
+            check_args(cmd, args, 1, None)?;
+            let cursor = args[0]
+                .parse::<u64>()
+                .map_err(|_| anyhow!("ERR invalid cursor"))?;
+            let (mut pattern, mut count, mut type_name) = (None, 10, None);
+            let mut options = args[1..].iter();
+            while let Some(option) = options.next() {
+                let value = options.next().ok_or(anyhow!("ERR syntax error"))?;
+                match option.to_uppercase().as_str() {
+                    "MATCH" => pattern = Some(value.as_str()),
+                    "COUNT" => count = parse_count(value)?,
+                    "TYPE" => type_name = Some(value.as_str()),
+                    _ => return Err(anyhow!("ERR syntax error")),
+                }
+            }
+            if count == 0 {
+                return Err(anyhow!("ERR syntax error"));
+            }
+            let (next, keys) = redis.scan(cursor, count, pattern, type_name);
+            Reply::Array(vec![Reply::Str(next.to_string()), Reply::strings(keys)])
+        }
+        RedisCommand::Rename | RedisCommand::RenameNx => {
+            check_args(cmd, args, 2, Some(2))?;
+            let renamed = redis.rename(&args[0], &args[1], cmd == RedisCommand::RenameNx)?;
+            // One record, so that the AOF never holds half of a rename
+            if renamed && args[0] != args[1] {
+                redis.propagate(record(cmd, args));
+            }
+            match cmd {
+                RedisCommand::Rename => Reply::ok(),
+                _ => Reply::Int(renamed as i64),
+            }
+        }
+        RedisCommand::Copy => {
+            check_args(cmd, args, 2, None)?;
+            let mut replace = false;
+            let mut db = None;
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match option.to_uppercase().as_str() {
+                    "REPLACE" => replace = true,
+                    "DB" => {
+                        let arg = options.next().ok_or(anyhow!("ERR syntax error"))?;
+                        db = Some(parse_db(redis, arg)?);
+                    }
+                    _ => return Err(anyhow!("ERR syntax error")),
+                }
+            }
+            let db = db.unwrap_or(redis.selected_db());
+            if args[0] == args[1] && db == redis.selected_db() {
+                return Err(anyhow!("ERR source and destination objects are the same"));
+            }
+            let copied = redis.copy(&args[0], &args[1], db, replace);
+            if copied {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(copied as i64)
+        }
+        RedisCommand::Move => {
+            check_args(cmd, args, 2, Some(2))?;
+            let db = parse_db(redis, &args[1])?;
+            let moved = redis.move_key(&args[0], db)?;
+            if moved {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(moved as i64)
+        }
+        RedisCommand::Select => {
+            check_args(cmd, args, 1, Some(1))?;
+            let db = parse_db(redis, &args[0])?;
+            redis.select(db)?;
+            Reply::ok()
+        }
+        RedisCommand::SwapDb => {
+            check_args(cmd, args, 2, Some(2))?;
+            let (a, b) = (parse_db(redis, &args[0])?, parse_db(redis, &args[1])?);
+            redis.swapdb(a, b)?;
+            redis.propagate(record(cmd, args));
+            Reply::ok()
+        }
+        RedisCommand::FlushDb => {
+            let lazy = parse_flush_mode(cmd, args)?;
+            redis.flushdb(lazy);
+            redis.propagate(record(cmd, &[]));
+            Reply::ok()
+        }
+        RedisCommand::FlushAll => {
+            let lazy = parse_flush_mode(cmd, args)?;
+            redis.flushall(lazy);
+            redis.propagate(record(cmd, &[]));
+            Reply::ok()
+        }
+        RedisCommand::Expire
+        | RedisCommand::PExpire
+        | RedisCommand::ExpireAt
+        | RedisCommand::PExpireAt => {
+            check_args(cmd, args, 2, None)?;
+            let time = parse_int(&args[1])? as i128;
+            let now = Redis::now() as i128;
+            let at = match cmd {
+                RedisCommand::Expire => now + time * 1000,
+                RedisCommand::PExpire => now + time,
+                RedisCommand::ExpireAt => time * 1000,
+                _ => time,
+            };
+            let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+            for flag in args[2..].iter() {
+                match flag.to_uppercase().as_str() {
+                    "NX" => nx = true,
+                    "XX" => xx = true,
+                    "GT" => gt = true,
+                    "LT" => lt = true,
+                    _ => return Err(anyhow!("ERR Unsupported option {}", flag)),
+                }
+            }
+            if nx && (xx || gt || lt) {
+                return Err(anyhow!(
+                    "ERR NX and XX, GT or LT options at the same time are not compatible"
+                ));
+            }
+            if gt && lt {
+                return Err(anyhow!(
+                    "ERR GT and LT options at the same time are not compatible"
+                ));
+            }
+            if !redis.exists(&args[0]) {
+                return Ok(Reply::Int(0));
+            }
+            // A key without expiry counts as one with an infinite TTL for GT and LT
+            let current = redis.expiry(&args[0]).map(|at| at as i128);
+            if (nx && current.is_some())
+                || (xx && current.is_none())
+                || (gt && current.is_none_or(|current| at <= current))
+                || (lt && current.is_some_and(|current| at >= current))
+            {
+                return Ok(Reply::Int(0));
+            }
+            if at <= now {
+                redis.del(&args[0]);
+            } else {
+                redis.set_expiry(&args[0], Some(at as Timestamp));
+            }
+            // Always logged as an absolute deadline, so that replaying does not extend it
+            redis.propagate(vec![
+                command_name(RedisCommand::PExpireAt),
+                args[0].to_string(),
+                at.to_string(),
+            ]);
+            Reply::Int(1)
+        }
+        RedisCommand::Ttl
+        | RedisCommand::PTtl
+        | RedisCommand::ExpireTime
+        | RedisCommand::PExpireTime => {
+            check_args(cmd, args, 1, Some(1))?;
+            if !redis.exists(&args[0]) {
+                return Ok(Reply::Int(-2));
+            }
+            let Some(at) = redis.expiry(&args[0]) else {
+                return Ok(Reply::Int(-1));
+            };
+            let left = at.saturating_sub(Redis::now());
+            Reply::Int(match cmd {
+                RedisCommand::Ttl => (left + 500) / 1000,
+                RedisCommand::PTtl => left,
+                RedisCommand::ExpireTime => at / 1000,
+                _ => at,
+            } as i64)
+        }
+        RedisCommand::Persist => {
+            check_args(cmd, args, 1, Some(1))?;
+            if redis.expiry(&args[0]).is_none() {
+                return Ok(Reply::Int(0));
+            }
+            redis.set_expiry(&args[0], None);
+            redis.propagate(record(cmd, args));
+            Reply::Int(1)
+        }
+        RedisCommand::Append => {
+            check_args(cmd, args, 2, Some(2))?;
+            let len = redis.append(&args[0], &args[1])?;
+            redis.propagate(record(cmd, args));
+            Reply::Int(len as i64)
+        }
+        RedisCommand::StrLen => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::Int(redis.strlen(&args[0])? as i64)
+        }
+        RedisCommand::GetRange => {
+            check_args(cmd, args, 3, Some(3))?;
+            let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+            let value = redis.get(&args[0])?.unwrap_or_default();
+            let bytes = value.as_bytes();
+            // Unlike the one of LRANGE, an end before the start of the string is the first byte
+            let stop = if stop < 0 { (stop + bytes.len() as i64).max(0) } else { stop };
+            Reply::Str(match Redis::range(start, stop, bytes.len()) {
+                Some((start, stop)) => String::from_utf8_lossy(&bytes[start..=stop]).into_owned(),
+                None => String::new(),
+            })
+        }
+        RedisCommand::SetRange => {
+            check_args(cmd, args, 3, Some(3))?;
+            let offset = parse_int(&args[1])?;
+            if offset < 0 || offset as usize + args[2].len() > MAX_STRING_LEN {
+                return Err(anyhow!("ERR offset is out of range"));
+            }
+            let len = redis.setrange(&args[0], offset as usize, &args[2])?;
+            if !args[2].is_empty() {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(len as i64)
+        }
+        RedisCommand::GetSet => {
+            check_args(cmd, args, 2, Some(2))?;
+            let old = redis.get(&args[0])?;
+            redis.set_at(&args[0], &args[1], 0);
+            redis.propagate(record(cmd, args));
+            old.map_or(Reply::Nil, Reply::Str)
+        }
+        RedisCommand::GetDel => {
+            check_args(cmd, args, 1, Some(1))?;
+            let value = redis.getdel(&args[0])?;
+            if value.is_some() {
+                redis.propagate(record(cmd, args));
+            }
+            value.map_or(Reply::Nil, Reply::Str)
+        }
+        RedisCommand::GetEx => {
+            check_args(cmd, args, 1, Some(3))?;
+            let expiry = match args.get(1).map(|option| option.to_uppercase()) {
+                None => None,
+                Some(option) if option == "PERSIST" && args.len() == 2 => Some(None),
+                Some(option) if args.len() == 3 => {
+                    let time = parse_int(&args[2])?;
+                    let time = u128::try_from(time)
+                        .ok()
+                        .filter(|time| *time > 0)
+                        .ok_or(anyhow!("ERR invalid expire time in 'getex' command"))?;
+                    Some(Some(match option.as_str() {
+                        "EX" => Redis::now() + time * 1000,
+                        "PX" => Redis::now() + time,
+                        "EXAT" => time * 1000,
+                        "PXAT" => time,
+                        _ => return Err(anyhow!("ERR syntax error")),
+                    }))
+                }
+                Some(_) => return Err(anyhow!("ERR syntax error")),
+            };
+            let value = redis.get(&args[0])?;
+            if let (Some(_), Some(expired_at)) = (&value, expiry) {
+                redis.set_expiry(&args[0], expired_at);
+                // Relative times are logged as absolute deadlines, for replay to agree
+                let mut record = record(cmd, &args[..1]);
+                match expired_at {
+                    Some(at) => record.extend(["PXAT".to_string(), at.to_string()]),
+                    None => record.push("PERSIST".to_string()),
+                }
+                redis.propagate(record);
+            }
+            value.map_or(Reply::Nil, Reply::Str)
+        }
+        RedisCommand::LPush | RedisCommand::RPush => {
+            check_args(cmd, args, 2, None)?;
+            let values: Vec<&str> = args[1..].iter().map(|v| v.as_str()).collect();
+            let len = if cmd == RedisCommand::LPush {
+                redis.lpush(&args[0], &values)?
+            } else {
+                redis.rpush(&args[0], &values)?
+            };
+            redis.propagate(record(cmd, args));
+            Reply::Int(len as i64)
+        }
+        RedisCommand::LPop | RedisCommand::RPop => {
+            check_args(cmd, args, 1, Some(2))?;
+            let count = args.get(1).map(|c| parse_count(c)).transpose()?;
+            let popped = redis.pop(&args[0], count.unwrap_or(1), cmd == RedisCommand::LPop)?;
+            if !popped.is_empty() {
+                redis.propagate(record(cmd, args));
+            }
+            match count {
+                None => popped.into_iter().next().map_or(Reply::Nil, Reply::Str),
+                Some(_) if popped.is_empty() => Reply::Nil,
+                Some(_) => Reply::strings(popped),
+            }
+        }
+        RedisCommand::LRange => {
+            check_args(cmd, args, 3, Some(3))?;
+            let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+            Reply::strings(redis.lrange(&args[0], start, stop)?)
+        }
+        RedisCommand::LLen => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::Int(redis.llen(&args[0])? as i64)
+        }
+        RedisCommand::LIndex => {
+            check_args(cmd, args, 2, Some(2))?;
+            let index = parse_int(&args[1])?;
+            redis
+                .lindex(&args[0], index)?
+                .map_or(Reply::Nil, Reply::Str)
+        }
+        RedisCommand::LSet => {
+            check_args(cmd, args, 3, Some(3))?;
+            let index = parse_int(&args[1])?;
+            redis.lset(&args[0], index, &args[2])?;
+            redis.propagate(record(cmd, args));
+            Reply::ok()
+        }
+        RedisCommand::LRem => {
+            check_args(cmd, args, 3, Some(3))?;
+            let count = parse_int(&args[1])?;
+            let removed = redis.lrem(&args[0], count, &args[2])?;
+            if removed > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(removed as i64)
+        }
+        RedisCommand::LTrim => {
+            check_args(cmd, args, 3, Some(3))?;
+            let (start, stop) = (parse_int(&args[1])?, parse_int(&args[2])?);
+            redis.ltrim(&args[0], start, stop)?;
+            redis.propagate(record(cmd, args));
+            Reply::ok()
+        }
+        RedisCommand::HSet => {
+            check_args(cmd, args, 3, None)?;
+            if args.len().is_multiple_of(2) {
+                return Err(anyhow!(
+                    "ERR wrong number of arguments for '{}' command",
+                    command_name(cmd).to_lowercase()
+                ));
+            }
+            let pairs: Vec<(&str, &str)> = args[1..]
+                .chunks(2)
+                .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+                .collect();
+            let added = redis.hset(&args[0], &pairs)?;
+            redis.propagate(record(cmd, args));
+            Reply::Int(added as i64)
+        }
+        RedisCommand::HGet => {
+            check_args(cmd, args, 2, Some(2))?;
+            redis
+                .hget(&args[0], &args[1])?
+                .map_or(Reply::Nil, Reply::Str)
+        }
+        RedisCommand::HmGet => {
+            check_args(cmd, args, 2, None)?;
+            let mut values = Vec::with_capacity(args.len() - 1);
+            for field in args[1..].iter() {
+                values.push(redis.hget(&args[0], field)?.map_or(Reply::Nil, Reply::Str));
+            }
+            Reply::Array(values)
+        }
+        RedisCommand::HDel => {
+            check_args(cmd, args, 2, None)?;
+            let fields: Vec<&str> = args[1..].iter().map(|f| f.as_str()).collect();
+            let removed = redis.hdel(&args[0], &fields)?;
+            if removed > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(removed as i64)
+        }
+        RedisCommand::HExists => {
+            check_args(cmd, args, 2, Some(2))?;
+            Reply::Int(redis.hget(&args[0], &args[1])?.is_some() as i64)
+        }
+        RedisCommand::HLen => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::Int(redis.hlen(&args[0])? as i64)
+        }
+        RedisCommand::HKeys | RedisCommand::HVals | RedisCommand::HGetAll => {
+            check_args(cmd, args, 1, Some(1))?;
+            let pairs = redis.hgetall(&args[0])?;
+            Reply::strings(match cmd {
+                RedisCommand::HKeys => pairs.into_iter().map(|(field, _)| field).collect(),
+                RedisCommand::HVals => pairs.into_iter().map(|(_, value)| value).collect(),
+                _ => pairs
+                    .into_iter()
+                    .flat_map(|(field, value)| [field, value])
+                    .collect(),
+            })
+        }
+        RedisCommand::HIncrBy => {
+            check_args(cmd, args, 3, Some(3))?;
+            let delta = parse_int(&args[2])?;
+            let value = redis.hincrby(&args[0], &args[1], delta)?;
+            redis.propagate(record(cmd, args));
+            Reply::Int(value)
+        }
+        RedisCommand::SAdd => {
+            check_args(cmd, args, 2, None)?;
+            let members: Vec<&str> = args[1..].iter().map(|m| m.as_str()).collect();
+            let added = redis.sadd(&args[0], &members)?;
+            if added > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(added as i64)
+        }
+        RedisCommand::SRem => {
+            check_args(cmd, args, 2, None)?;
+            let members: Vec<&str> = args[1..].iter().map(|m| m.as_str()).collect();
+            let removed = redis.srem(&args[0], &members)?;
+            if removed > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(removed as i64)
+        }
+        RedisCommand::SIsMember => {
+            check_args(cmd, args, 2, Some(2))?;
+            Reply::Int(redis.sismember(&args[0], &args[1])? as i64)
+        }
+        RedisCommand::SmIsMember => {
+            check_args(cmd, args, 2, None)?;
+            let mut found = Vec::with_capacity(args.len() - 1);
+            for member in args[1..].iter() {
+                found.push(Reply::Int(redis.sismember(&args[0], member)? as i64));
+            }
+            Reply::Array(found)
+        }
+        RedisCommand::SCard => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::Int(redis.scard(&args[0])? as i64)
+        }
+        RedisCommand::SMembers => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::strings(redis.smembers(&args[0])?.into_iter().collect())
+        }
+        RedisCommand::SPop => {
+            check_args(cmd, args, 1, Some(2))?;
+            let count = args.get(1).map(|c| parse_count(c)).transpose()?;
+            let popped = redis.spop(&args[0], count.unwrap_or(1))?;
+            // Popped members are random: propagate what was actually removed
+            if !popped.is_empty() {
+                let mut srem = vec![command_name(RedisCommand::SRem), args[0].to_string()];
+                srem.extend(popped.iter().cloned());
+                redis.propagate(srem);
+            }
+            match count {
+                None => popped.into_iter().next().map_or(Reply::Nil, Reply::Str),
+                Some(_) => Reply::strings(popped),
+            }
+        }
+        RedisCommand::SRandMember => {
+            check_args(cmd, args, 1, Some(2))?;
+            let count = args.get(1).map(|c| parse_int(c)).transpose()?;
+            let members = redis.srandmember(&args[0], count.unwrap_or(1))?;
+            match count {
+                None => members.into_iter().next().map_or(Reply::Nil, Reply::Str),
+                Some(_) => Reply::strings(members),
+            }
+        }
+        RedisCommand::SInter | RedisCommand::SUnion | RedisCommand::SDiff => {
+            check_args(cmd, args, 1, None)?;
+            let keys: Vec<&str> = args.iter().map(|k| k.as_str()).collect();
+            let result = redis.set_algebra(set_op(cmd), &keys)?;
+            Reply::strings(result.into_iter().collect())
+        }
+        RedisCommand::SInterStore | RedisCommand::SUnionStore | RedisCommand::SDiffStore => {
+            check_args(cmd, args, 2, None)?;
+            let keys: Vec<&str> = args[1..].iter().map(|k| k.as_str()).collect();
+            let result = redis.set_algebra(set_op(cmd), &keys)?;
+            let len = redis.store_set(&args[0], result);
+            redis.propagate(record(cmd, args));
+            Reply::Int(len as i64)
+        }
+        RedisCommand::ZAdd => {
+            check_args(cmd, args, 3, None)?;
+            let mut flags = ZAddFlags::default();
+            let mut changed_only = false;
+            let mut rest = &args[1..];
+            while let Some(option) = rest.first() {
+                match option.to_uppercase().as_str() {
+                    "NX" => flags.nx = true,
+                    "XX" => flags.xx = true,
+                    "GT" => flags.gt = true,
+                    "LT" => flags.lt = true,
+                    "CH" => changed_only = true,
+                    "INCR" => flags.incr = true,
+                    _ => break,
+                }
+                rest = &rest[1..];
+            }
+            if rest.is_empty() || !rest.len().is_multiple_of(2) {
+                return Err(anyhow!("ERR syntax error"));
+            }
+            if flags.nx && (flags.xx || flags.gt || flags.lt) || (flags.gt && flags.lt) {
+                return Err(anyhow!(
+                    "ERR GT, LT, and/or NX options at the same time are not compatible"
+                ));
+            }
+            if flags.incr && rest.len() > 2 {
+                return Err(anyhow!(
+                    "ERR INCR option supports a single increment-element pair"
+                ));
+            }
+            let mut pairs = Vec::with_capacity(rest.len() / 2);
+            for pair in rest.chunks(2) {
+                pairs.push((parse_float(&pair[0])?, pair[1].as_str()));
+            }
+            let (added, updated, last) = redis.zadd(&args[0], &flags, &pairs)?;
+            if added + updated > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            if flags.incr {
+                last.map_or(Reply::Nil, score_reply)
+            } else if changed_only {
+                Reply::Int((added + updated) as i64)
+            } else {
+                Reply::Int(added as i64)
+            }
+        }
+        RedisCommand::ZIncrBy => {
+            check_args(cmd, args, 3, Some(3))?;
+            let flags = ZAddFlags {
+                incr: true,
+                ..Default::default()
+            };
+            let delta = parse_float(&args[1])?;
+            let (_, _, score) = redis.zadd(&args[0], &flags, &[(delta, &args[2])])?;
+            redis.propagate(record(cmd, args));
+            score.map_or(Reply::Nil, score_reply)
+        }
+        RedisCommand::ZRem => {
+            check_args(cmd, args, 2, None)?;
+            let members: Vec<&str> = args[1..].iter().map(|m| m.as_str()).collect();
+            let removed = redis.zrem(&args[0], &members)?;
+            if removed > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(removed as i64)
+        }
+        RedisCommand::ZScore => {
+            check_args(cmd, args, 2, Some(2))?;
+            let score = redis.zset(&args[0])?.and_then(|zset| zset.score(&args[1]));
+            score.map_or(Reply::Nil, score_reply)
+        }
+        RedisCommand::ZCard => {
+            check_args(cmd, args, 1, Some(1))?;
+            Reply::Int(redis.zset(&args[0])?.map_or(0, |zset| zset.len()) as i64)
+        }
+        RedisCommand::ZRank | RedisCommand::ZRevRank => {
+            check_args(cmd, args, 2, Some(2))?;
+            let rank = redis.zset(&args[0])?.and_then(|zset| {
+                let rank = zset.rank(&args[1])?;
+                Some(if cmd == RedisCommand::ZRevRank {
+                    zset.len() - 1 - rank
+                } else {
+                    rank
+                })
+            });
+            rank.map_or(Reply::Nil, |rank| Reply::Int(rank as i64))
+        }
+        RedisCommand::ZRange | RedisCommand::ZRangeByScore => {
+            check_args(cmd, args, 3, None)?;
+            zrange(
+                redis,
+                &args[0],
+                (&args[1], &args[2]),
+                &args[3..],
+                cmd == RedisCommand::ZRangeByScore,
+            )?
+        }
+        RedisCommand::ZCount => {
+            check_args(cmd, args, 3, Some(3))?;
+            let (min, max) = (parse_score_bound(&args[1])?, parse_score_bound(&args[2])?);
+            let count = redis.zset(&args[0])?.map_or(0, |zset| {
+                let (start, end) = zset.ranks_by_score(min, max);
+                end - start
+            });
+            Reply::Int(count as i64)
+        }
+        RedisCommand::ZPopMin | RedisCommand::ZPopMax => {
+            check_args(cmd, args, 1, Some(2))?;
+            let count = args.get(1).map(|c| parse_count(c)).transpose()?;
+            let popped = redis.zremrange(
+                &args[0],
+                (0, count.unwrap_or(1)),
+                cmd == RedisCommand::ZPopMax,
+            )?;
+            if !popped.is_empty() {
+                redis.propagate(record(cmd, args));
+            }
+            pairs_reply(popped, true)
+        }
+        RedisCommand::ZRemRangeByScore => {
+            check_args(cmd, args, 3, Some(3))?;
+            let (min, max) = (parse_score_bound(&args[1])?, parse_score_bound(&args[2])?);
+            let ranks = redis
+                .zset(&args[0])?
+                .map_or((0, 0), |zset| zset.ranks_by_score(min, max));
+            let removed = redis.zremrange(&args[0], ranks, false)?.len();
+            if removed > 0 {
+                redis.propagate(record(cmd, args));
+            }
+            Reply::Int(removed as i64)
+        }
+        _ => return Err(anyhow!("Unsupported command `{}`", command_name(cmd))),
+    };
+    // Values modified in place are sized again
+    if is_write(cmd) {
+        for key in command_keys(cmd, args) {
+            redis.account(key);
+        }
+    }
+    Ok(reply)
+}
+
+fn set_op(cmd: RedisCommand) -> SetOp {
+    match cmd {
+        RedisCommand::SInter | RedisCommand::SInterStore => SetOp::Inter,
+        RedisCommand::SUnion | RedisCommand::SUnionStore => SetOp::Union,
+        _ => SetOp::Diff,
+    }
+}
+
+/// Apply a record read back from the AOF. Nothing is propagated
+pub fn replay(redis: &mut Redis, record: &[String]) -> Result<()> {
+    if let [cmd, keys @ ..] = record {
+        if cmd == "DEL" {
+            for key in keys {
+                redis.del(key);
+            }
+            return Ok(());
+        }
+    }
+    let (name, args) = record.split_first().ok_or(anyhow!("Empty record"))?;
+    let cmd = parse_command(name).ok_or(anyhow!("Unknown command `{name}`"))?;
+    let args: Vec<FastStr> = args.iter().map(|arg| arg.clone().into()).collect();
+    let replaying = redis.set_replaying(true);
+    let result = execute(redis, cmd, &args);
+    redis.set_replaying(replaying);
+    redis.take_effects();
+    result.map(|_| ())
+}
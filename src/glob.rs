@@ -0,0 +1,124 @@
+//! Glob-style patterns of KEYS and SCAN: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+
+/// Whether `s` matches `pattern` as a whole
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    // Position of the last `*` and where it started matching, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() {
+            if p[pi] == b'*' {
+                star = Some((pi, si));
+                pi += 1;
+                continue;
+            }
+            if let Some(next) = match_one(p, pi, s[si]) {
+                pi = next;
+                si += 1;
+                continue;
+            }
+        }
+        // Let the last `*` swallow one more byte
+        match star {
+            Some((sp, ss)) => {
+                star = Some((sp, ss + 1));
+                pi = sp + 1;
+                si = ss + 1;
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+/// Match `c` against the single-byte element starting at `p[pi]`.
+/// return: the index after the element if it matches
+fn match_one(p: &[u8], pi: usize, c: u8) -> Option<usize> {
+    match p[pi] {
+        b'?' => Some(pi + 1),
+        b'\\' if pi + 1 < p.len() => (p[pi + 1] == c).then_some(pi + 2),
+        b'[' => {
+            let mut i = pi + 1;
+            let negate = p.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            // An unterminated class runs to the end of the pattern
+            while i < p.len() && p[i] != b']' {
+                if p[i] == b'\\' && i + 1 < p.len() {
+                    matched |= p[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < p.len() && p[i + 1] == b'-' && p[i + 2] != b']' {
+                    let (lo, hi) = (p[i].min(p[i + 2]), p[i].max(p[i + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= p[i] == c;
+                    i += 1;
+                }
+            }
+            (matched != negate).then_some((i + 1).min(p.len()))
+        }
+        other => (other == c).then_some(pi + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(glob_match("hello", "hello"));
+        assert!(!glob_match("hello", "hello!"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h*", "h"));
+        assert!(glob_match("h*o", "hello"));
+        assert!(!glob_match("h*o", "hellx"));
+        assert!(glob_match("**", "abc"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn stars_backtrack() {
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(glob_match("a*b*c", "abbbcbc"));
+        assert!(!glob_match("a*b*c", "abbbcb"));
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("user:*:name", "user:1:2:name"));
+        assert!(!glob_match("user:*:name", "user:1:names"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(!glob_match("[a-c]x", "dx"));
+        // Reversed ranges work both ways
+        assert!(glob_match("[c-a]x", "bx"));
+        // A `-` at the end of a class is itself
+        assert!(glob_match("[a-]", "-"));
+        assert!(glob_match("[\\]]", "]"));
+        // An unterminated class runs to the end of the pattern
+        assert!(glob_match("a[bc", "ab"));
+        assert!(!glob_match("a[bc", "ad"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(glob_match("a\\?", "a?"));
+        assert!(!glob_match("a\\?", "ab"));
+        assert!(glob_match("\\[x]", "[x]"));
+        // A trailing backslash is itself
+        assert!(glob_match("a\\", "a\\"));
+    }
+}
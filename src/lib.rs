@@ -1,31 +1,41 @@
 #![feature(impl_trait_in_assoc_type)]
 
+pub mod aof;
 pub mod cmdargs;
-mod redis;
+pub mod command;
+mod glob;
+pub mod redis;
+pub mod replication;
+pub mod snapshot;
+mod zset;
 
 use anyhow::{ anyhow, Ok };
 use clap::{ self, Parser };
-use cmdargs::ServerConfig;
+use cmdargs::{ AppendFsync, ServerConfig };
 use lazy_static::lazy_static;
 use nanoid::nanoid;
 use pilota::FastStr;
-use redis::Timestamp;
+use aof::{ AofMessage, AofPosition, AofStatus, AofWriter };
+use replication::{ OutputLimit, Replication, Transfer };
+use snapshot::SaveStatus;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
 use std::{
-    fs::OpenOptions,
-    io::Write,
     net::SocketAddr,
-    time::{ Duration, Instant, SystemTime, UNIX_EPOCH },
+    path::Path,
+    time::Duration,
 };
-use tokio::sync::mpsc;
-use tokio::{ signal, sync::Mutex };
+use tokio::sync::{ mpsc, oneshot };
+use tokio::{ signal, sync::{ Mutex, MutexGuard } };
 use tracing::info;
 use uuid::Uuid;
 use volo::net::Address;
-use volo_gen::volo::redis::{ GetItemRequest, GetItemResponse, MultiGetItemResponse, RedisCommand };
+use volo_gen::volo::redis::{
+    GetItemRequest, GetItemResponse, MultiGetItemResponse, RedisCommand, SyncChunkRequest, SyncEnd
+};
 
 pub type Host = IpAddr;
 pub type Port = u16;
@@ -55,8 +65,54 @@ pub fn get_client(addr: impl Into<Address>) -> volo_gen::volo::redis::ItemServic
         .build()
 }
 type AMutex<T> = Arc<Mutex<T>>;
+/// Period of the active expiry cycle
+const EXPIRE_TICK_MS: u64 = 100;
+/// At most this many keys are expired per cycle, the rest wait for the next ones
+const EXPIRE_KEYS_PER_TICK: usize = 1000;
+/// Period of the check for an automatic AOF rewrite
+const AOF_REWRITE_CHECK_MS: u64 = 1000;
+/// Period of the check of the `--save` schedules
+const SAVE_CHECK_MS: u64 = 1000;
+/// Attempts to reach a replica before dropping it, a second apart
+const REPLICA_ATTEMPTS: usize = 5;
+/// Period of the empty stream sent to an idle replica, which tells it the master is there
+const REPL_PING_SECS: u64 = 10;
+/// Keys copied per hold of the data lock for a save, an AOF rewrite or a full sync,
+/// the commands run in between
+const COPY_STEP_KEYS: usize = 1000;
+/// Bytes of data per request of a full sync
+const SYNC_CHUNK_BYTES: usize = 1 << 20;
+/// Period of the check for a save under way to finish
+const SAVE_WAIT_MS: u64 = 100;
+/// Error of a save while one is under way
+const SAVE_BUSY: &str = "ERR Background save already in progress";
+
+pub fn aof_path() -> String {
+    CMD_ARGS.aof.clone().unwrap_or_else(|| format!("{}.aof", NAME.as_deref().unwrap_or("server")))
+}
+
+pub fn snapshot_path() -> String {
+    format!("{}.snap", NAME.as_deref().unwrap_or("server"))
+}
+
+/// Unix time in seconds
+fn unix_time() -> u64 {
+    (redis::Redis::now() / 1000) as u64
+}
+
+/// Lock the data, with the database `db` of a request (0 if not set) selected
+async fn lock_db(db: Option<i32>) -> anyhow::Result<MutexGuard<'static, redis::Redis>> {
+    let mut redis = REDIS.lock().await;
+    let db = usize::try_from(db.unwrap_or(0)).map_err(|_| anyhow!("ERR DB index is out of range"))?;
+    redis.select(db)?;
+    Ok(redis)
+}
 lazy_static! {
-    static ref REDIS: AMutex<redis::Redis> = Arc::new(Mutex::new(redis::Redis::new()));
+    static ref REDIS: AMutex<redis::Redis> = {
+        let mut redis = redis::Redis::new(CMD_ARGS.databases);
+        redis.set_maxmemory(CMD_ARGS.maxmemory, CMD_ARGS.maxmemory_policy, CMD_ARGS.maxmemory_samples);
+        Arc::new(Mutex::new(redis))
+    };
     // Command line args
     static ref CMD_ARGS: ServerConfig = ServerConfig::parse();
 
@@ -81,12 +137,26 @@ lazy_static! {
     pub static ref KEY_WATCHED: Arc<Mutex<HashMap<String, Vec<String>>>> = Arc::new(Mutex::new(HashMap::new()));
 }
 
+#[derive(Clone)]
 pub struct S {
     pub redis: &'static AMutex<redis::Redis>,
-    sender: AMutex<mpsc::Sender<String>>,
+    sender: AMutex<mpsc::Sender<AofMessage>>,
     pub state: AMutex<RedisState>,
     pub uuid: AMutex<Uuid>, // TODO: remove this lock as it will only be modify once by main thread
     pub client_addrs: AMutex<HashMap<Uuid, SocketAddr>>,
+    aof_status: Arc<AofStatus>,
+    save_status: Arc<SaveStatus>,
+    /// The stream of writes, fed under the data lock so that it follows their order
+    repl: Arc<parking_lot::Mutex<Replication>>,
+    /// As a replica, unix time of the last message from the master
+    master_seen: Arc<AtomicU64>,
+    /// As a replica, the data of a full sync being received
+    transfer: Arc<parking_lot::Mutex<Transfer>>,
+}
+/// What the reply to a command waits for, once its records are made
+struct Effects {
+    /// Under `appendfsync always`, told once the records are synced to AOF
+    synced: Option<oneshot::Receiver<Result<(), String>>>,
 }
 pub struct Transaction {
     pub commands: Vec<GetItemRequest>,
@@ -95,56 +165,48 @@ pub struct Transaction {
 impl S {
     pub async fn new() -> S {
         let (sender, mut receiver) = mpsc::channel(1024);
-        // Spawn a thread to periodically flush the data into AOF file
+        let aof_status = Arc::new(AofStatus::default());
+        let status = aof_status.clone();
+        // Spawn a task writing the AOF, woken by the writes and once a second
         tokio::spawn(async move {
-            let mut command: Vec<String> = Vec::new();
-            let mut aof_file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(
-                    format!("{}.aof", if NAME.is_none() {
-                        "server"
-                    } else {
-                        NAME.as_ref().unwrap().as_str()
-                    })
-                )
+            if !CMD_ARGS.appendonly {
+                // Dropping the receiver discards the messages
+                return;
+            }
+            let mut writer = AofWriter::open(aof_path(), CMD_ARGS.appendfsync, status)
                 .expect("Failed to open AOF file");
-            let mut last_write_time = Instant::now();
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
             loop {
-                // Flush contents inside command buffer
-                let mut flush = |command: &Vec<String>| {
-                    if command.is_empty() {
-                        return;
-                    }
-                    // 将缓存中的操作写入 AOF 文件
-                    command.iter().for_each(|cmd| {
-                        write!(aof_file, "{}", cmd).expect("Failed to write to AOF file");
-                    });
-                    aof_file.flush().expect("Failed to flush file");
-                    println!("COMMAND SAVED!!");
-                };
-                match receiver.try_recv() {
-                    std::result::Result::Ok(msg) => {
-                        if msg == "SHUTDOWN".to_string() {
-                            flush(&command);
-                            command.clear();
-                            info!("shutdown finally");
+                tokio::select! {
+                    msg = receiver.recv() => {
+                        let Some(mut msg) = msg else {
                             break;
-                        } else {
-                            command.push(msg);
-                            // 检查是否距上次写入已经过去了 1 秒
-                            if last_write_time.elapsed() >= Duration::from_secs(1) {
-                                flush(&command);
-                                command.clear();
-                                last_write_time = Instant::now();
+                        };
+                        // Group commit: the writes queued meanwhile share one write and one sync
+                        loop {
+                            match msg {
+                                AofMessage::Append(records, ack) => writer.append(records, ack),
+                                AofMessage::RewriteStart => writer.start_rewrite(),
+                                AofMessage::RewriteDone(temp) => writer.finish_rewrite(&temp),
+                                AofMessage::RewriteFailed => writer.abort_rewrite(),
+                                AofMessage::Mark(reply) => {
+                                    let _ = reply.send(writer.position());
+                                }
+                                AofMessage::Loaded => writer.reload(),
+                                AofMessage::Shutdown => {
+                                    writer.shutdown();
+                                    info!("shutdown finally");
+                                    return;
+                                }
+                            }
+                            match receiver.try_recv() {
+                                std::result::Result::Ok(next) => msg = next,
+                                Err(_) => break,
                             }
                         }
+                        writer.commit();
                     }
-                    Err(_) => {
-                        flush(&command);
-                        command.clear();
-                        last_write_time = Instant::now();
-                    }
+                    _ = ticker.tick() => writer.tick(),
                 }
             }
         });
@@ -155,30 +217,543 @@ impl S {
             state: Arc::new(Mutex::new(RedisState::Single)),
             uuid: Arc::new(Mutex::new(Uuid::nil())),
             client_addrs: Arc::new(Mutex::new(HashMap::new())),
+            aof_status,
+            save_status: Arc::new(SaveStatus::new(unix_time())),
+            repl: Arc::new(parking_lot::Mutex::new(Replication::new(
+                Uuid::new_v4().to_string(),
+                0,
+                CMD_ARGS.repl_backlog_size,
+                OutputLimit {
+                    hard: CMD_ARGS.replica_output_limit,
+                    soft: CMD_ARGS.replica_output_soft_limit,
+                    soft_seconds: CMD_ARGS.replica_output_soft_seconds,
+                }
+            ))),
+            master_seen: Arc::new(AtomicU64::new(0)),
+            transfer: Arc::new(parking_lot::Mutex::new(Transfer::default())),
         };
+        let expirer = s.clone();
+        tokio::spawn(async move { expirer.expire_keys().await });
+        if CMD_ARGS.appendonly {
+            let rewriter = s.clone();
+            tokio::spawn(async move { rewriter.auto_rewrite_aof().await });
+        }
+        let saver = s.clone();
+        tokio::spawn(async move { saver.auto_save().await });
+        let watcher = s.clone();
+        tokio::spawn(async move { watcher.watch_master().await });
         // pre-run commands,
         // TODO
         // ...
 
-        // if SLAVE: then sync
+        if SLAVE_OF.is_none() {
+            // Gen for no-slaves
+            *s.uuid.lock().await = Uuid::new_v4();
+        }
+        s
+    }
+    /// Once the data is loaded: take up the replication stream where the data is at,
+    /// if known, and if SLAVE: sync from there
+    pub async fn resume_replication(&self, position: Option<(String, u64)>) {
+        if let Some((id, offset)) = position {
+            self.repl.lock().reset(id, offset);
+        }
         if let Some(master) = SLAVE_OF.clone() {
             let addr: SocketAddr = master.parse().unwrap();
-            s.react_to_command(GetItemRequest {
+            self.react_to_command(GetItemRequest {
                 cmd: RedisCommand::Replicaof,
                 args: Some(vec![addr.ip().to_string().into(), addr.port().to_string().into()]),
                 client_id: None,
                 transaction_id: None,
+                db: None,
             }).await.expect("Failed to execute init commands!");
             println!("Sync send.");
-        } else {
-            // Gen for no-slaves
-            *s.uuid.lock().await = Uuid::new_v4();
         }
-        s
     }
-    async fn send_message(&self, msg: String) {
+    async fn send_message(&self, msg: AofMessage) {
         let _ = self.sender.lock().await.send(msg).await;
     }
+    /// The AOF was replayed at boot: from now on it only grows by the writes.
+    /// With `rewrite`, data was loaded from elsewhere, and the AOF is rewritten to hold it
+    pub async fn aof_loaded(&self, rewrite: bool) {
+        self.send_message(AofMessage::Loaded).await;
+        if rewrite {
+            info!("Rewriting AOF with the data loaded");
+            let _ = self.rewrite_aof().await;
+        }
+    }
+    /// Active expiry: reclaim expired keys in the background, a bounded number per tick,
+    /// and log / forward a DEL for each of them
+    async fn expire_keys(&self) {
+        let mut ticker = tokio::time::interval(Duration::from_millis(EXPIRE_TICK_MS));
+        loop {
+            ticker.tick().await;
+            let effects = {
+                let mut redis = REDIS.lock().await;
+                redis.expire_cycle(EXPIRE_KEYS_PER_TICK);
+                self.take_effects(&mut redis).await
+            };
+            let _ = self.propagate(effects).await;
+        }
+    }
+    /// Start an AOF rewrite once the file grew enough since the last one
+    async fn auto_rewrite_aof(&self) {
+        let mut ticker = tokio::time::interval(Duration::from_millis(AOF_REWRITE_CHECK_MS));
+        loop {
+            ticker.tick().await;
+            let needed = self.aof_status.needs_rewrite(
+                CMD_ARGS.auto_aof_rewrite_percentage,
+                CMD_ARGS.auto_aof_rewrite_min_size as u64
+            );
+            if needed {
+                info!("Starting automatic AOF rewrite");
+                let _ = self.rewrite_aof().await;
+            }
+        }
+    }
+    /// Save a snapshot once one of the `--save` schedules is due
+    async fn auto_save(&self) {
+        let schedules: Vec<(u64, u64)> = CMD_ARGS.save
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        if schedules.is_empty() {
+            return;
+        }
+        let mut ticker = tokio::time::interval(Duration::from_millis(SAVE_CHECK_MS));
+        loop {
+            ticker.tick().await;
+            if self.save_status.due(&schedules, unix_time()) {
+                info!("Starting scheduled snapshot");
+                let _ = self.save(true).await;
+            }
+        }
+    }
+    /// SAVE / BGSAVE: write a snapshot of the data as of now, along with the AOF position
+    /// it matches. The data is copied a few keys at a time, the commands running in between,
+    /// and written out in the background; unless `background`, the save is waited for
+    async fn save(&self, background: bool) -> anyhow::Result<()> {
+        if self.save_status.saving.swap(true, Ordering::Relaxed) {
+            return Err(anyhow!(SAVE_BUSY));
+        }
+        let (handle, mark, dirty, (repl_id, repl_offset)) = {
+            let mut redis = REDIS.lock().await;
+            // Under the lock, so that the position is right after the records of the data copied
+            let (reply, mark) = oneshot::channel();
+            if CMD_ARGS.appendonly {
+                self.send_message(AofMessage::Mark(reply)).await;
+            } else {
+                // No AOF: the snapshot holds all the data
+                let _ = reply.send(AofPosition::default());
+            }
+            let repl = self.repl.lock();
+            let position = (repl.id.clone(), repl.offset());
+            drop(repl);
+            (redis.start_copy(), mark, self.save_status.dirty.load(Ordering::Relaxed), position)
+        };
+        let status = self.save_status.clone();
+        let task = tokio::spawn(async move {
+            let snapshot = copy_snapshot(handle).await;
+            let Some(position) = mark.await.ok() else {
+                status.saved(&Err(std::io::ErrorKind::BrokenPipe.into()), dirty, unix_time());
+                return Err(anyhow!("ERR the AOF is closed"));
+            };
+            let meta = snapshot::Meta {
+                created_at: unix_time(),
+                repl_id,
+                repl_offset,
+                aof_offset: position.offset,
+                aof_digest: position.digest,
+            };
+            let write = tokio::task::spawn_blocking(move || {
+                let result = snapshot::write(&snapshot_path(), snapshot.into_databases(), &meta);
+                match &result {
+                    std::result::Result::Ok(()) => info!("Snapshot saved"),
+                    Err(e) => tracing::error!("Failed to save snapshot: {}", e),
+                }
+                status.saved(&result, dirty, unix_time());
+                result
+            });
+            Ok(write.await??)
+        });
+        if !background {
+            task.await??;
+        }
+        Ok(())
+    }
+    /// BGREWRITEAOF: write the fewest records rebuilding a copy of the data in the background,
+    /// copied a few keys at a time, the commands running in between.
+    /// Writes made meanwhile are appended to both the current AOF and a buffer, which ends
+    /// the new file before it replaces the current one
+    async fn rewrite_aof(&self) -> anyhow::Result<()> {
+        if !CMD_ARGS.appendonly {
+            return Err(anyhow!("ERR AOF is turned off, start with `--appendonly yes`"));
+        }
+        if self.aof_status.rewriting.swap(true, Ordering::Relaxed) {
+            return Err(anyhow!("ERR Background append only file rewriting already in progress"));
+        }
+        let handle = {
+            let mut redis = REDIS.lock().await;
+            // Under the lock, so that the writes buffered are exactly the ones after the copy
+            self.send_message(AofMessage::RewriteStart).await;
+            redis.start_copy()
+        };
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let snapshot = copy_snapshot(handle).await;
+            tokio::task::spawn_blocking(move || {
+                // Next to the AOF, to be renamed over it
+                let temp = Path::new(&aof_path())
+                    .with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()))
+                    .to_string_lossy()
+                    .into_owned();
+                let msg = match aof::write_rewrite(&temp, snapshot.records()) {
+                    std::result::Result::Ok(()) => AofMessage::RewriteDone(temp),
+                    Err(e) => {
+                        tracing::error!("Failed to rewrite AOF: {}", e);
+                        let _ = std::fs::remove_file(&temp);
+                        AofMessage::RewriteFailed
+                    }
+                };
+                let _ = sender.blocking_lock().blocking_send(msg);
+            });
+        });
+        Ok(())
+    }
+    /// Drain the effects of a command while the data is still locked, so that they reach AOF
+    /// and the replication stream in the order they were made.
+    /// In AOF, records of other databases than 0 follow a SELECT, and the batch ends in database 0.
+    /// return: what the reply waits for
+    async fn take_effects(&self, redis: &mut redis::Redis) -> Effects {
+        let records = redis.take_effects();
+        if records.is_empty() {
+            return Effects { synced: None };
+        }
+        let mut aof = String::new();
+        let mut selected = 0;
+        for (db, record) in records.iter() {
+            if *db != selected {
+                aof += &aof::encode(&["SELECT".to_string(), db.to_string()]);
+                selected = *db;
+            }
+            aof += &aof::encode(record);
+        }
+        if selected != 0 {
+            aof += &aof::encode(&["SELECT".to_string(), "0".to_string()]);
+        }
+        let (ack, synced) = match CMD_ARGS.appendfsync {
+            AppendFsync::Always if CMD_ARGS.appendonly => {
+                let (ack, synced) = oneshot::channel();
+                (Some(ack), Some(synced))
+            }
+            _ => (None, None),
+        };
+        self.save_status.dirty.fetch_add(records.len() as u64, Ordering::Relaxed);
+        self.feed_replicas(&aof);
+        self.send_message(AofMessage::Append(aof, ack)).await;
+        Effects { synced }
+    }
+    /// Append to the replication stream, the same bytes as to AOF
+    fn feed_replicas(&self, stream: &str) {
+        self.repl.lock().feed(stream);
+    }
+    /// Under `appendfsync always`, wait for the records to be synced to AOF, so that the reply
+    /// follows. Replicas are sent the records by their own tasks, see `feed_replica`
+    async fn propagate(&self, effects: Effects) -> anyhow::Result<()> {
+        match effects.synced {
+            Some(synced) => match synced.await {
+                std::result::Result::Ok(result) => result.map_err(misconf),
+                Err(_) => Err(misconf("the AOF is closed".to_string())),
+            },
+            None => Ok(()),
+        }
+    }
+    /// Ask the master to sync this node, from where the data is at so that it only sends
+    /// what the data lacks if it can
+    async fn sync_with_master(&self, master: SocketAddr) -> anyhow::Result<()> {
+        let self_addr: SocketAddr = SELF_PUB_ADDR.parse().unwrap();
+        let (repl_id, repl_offset) = {
+            let repl = self.repl.lock();
+            (repl.id.clone(), repl.offset())
+        };
+        // TODO: replace the global client as a performance boost
+        let resp = get_client(master).get_item(
+            volo_gen::volo::redis::GetItemRequest {
+                cmd: RedisCommand::Sync,
+                args: Some(
+                    vec![
+                        self_addr.ip().to_string().into(),
+                        self_addr.port().to_string().into(),
+                        repl_id.into(),
+                        repl_offset.to_string().into()
+                    ]
+                ),
+                client_id: None,
+                transaction_id: None,
+                db: None,
+            }
+        ).await?;
+
+        if !resp.ok {
+            return Err(anyhow!("Sync failed: error at server side"));
+        }
+        let uuid = resp.data.unwrap();
+        *self.uuid.lock().await = Uuid::from_str(&uuid.to_string())?;
+        self.master_seen.store(unix_time(), Ordering::Relaxed);
+        Ok(())
+    }
+    /// As a replica, sync again once the master was silent for `--repl-timeout` seconds,
+    /// as after it disconnected this node
+    async fn watch_master(&self) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let master = match *self.state.lock().await {
+                RedisState::SlaveOf(host, port) => SocketAddr::new(host, port),
+                _ => continue,
+            };
+            let silent = unix_time().saturating_sub(self.master_seen.load(Ordering::Relaxed));
+            if silent < CMD_ARGS.repl_timeout {
+                continue;
+            }
+            info!("No news from master {} for {}s, syncing again", master, silent);
+            // Next attempt a timeout later
+            self.master_seen.store(unix_time(), Ordering::Relaxed);
+            if let Err(e) = self.sync_with_master(master).await {
+                tracing::warn!("Failed to sync with master {}: {}", master, e);
+            }
+        }
+    }
+    /// Keep the replica at `addr` up to date: send it the replication stream from `offset` on,
+    /// or all the data first if None or no longer in the backlog, then the stream queued for it.
+    /// Ends once the replica can't be reached, or is disconnected: for exceeding the output
+    /// limits, or because it synced again meanwhile, replacing `client`
+    async fn feed_replica(&self, client: Uuid, addr: SocketAddr, mut offset: Option<u64>) {
+        let replica = get_client(addr);
+        let send = |cmd, args: Vec<FastStr>| {
+            let replica = replica.clone();
+            async move {
+                call_replica(addr, || {
+                    replica.get_item(GetItemRequest {
+                        cmd,
+                        args: Some(args.clone()),
+                        client_id: None,
+                        transaction_id: None,
+                        db: None,
+                    })
+                })
+                .await
+            }
+        };
+        'sync: loop {
+            // What to send first and the queue of what follows, taken together under the data
+            // lock, which the stream is fed under. All the data is copied after, a bit at a time
+            let (stream, copy, id, mut at, mut queue) = {
+                let mut redis = REDIS.lock().await;
+                let mut repl = self.repl.lock();
+                // Records are UTF-8: bytes which are not start within a character, at an offset
+                // the stream was never cut at, and the replica is sent all the data instead
+                let stream = offset
+                    .and_then(|offset| repl.backlog.since(offset))
+                    .and_then(|bytes| String::from_utf8(bytes).ok());
+                let copy = stream.is_none().then(|| redis.start_copy());
+                let at = offset.filter(|_| stream.is_some()).unwrap_or(repl.offset());
+                (stream, copy, repl.id.clone(), at, repl.attach(client))
+            };
+            let mut resp = match (stream, copy) {
+                (Some(stream), _) => {
+                    let len = stream.len() as u64;
+                    let args = vec![id.clone().into(), at.to_string().into(), stream.into()];
+                    let resp = send(RedisCommand::ReplStream, args).await;
+                    at += len;
+                    resp
+                }
+                (None, copy) => {
+                    let data = copy_data(copy.unwrap()).await;
+                    send_data(&replica, addr, data, &id, at).await
+                }
+            };
+            loop {
+                match resp {
+                    Some(resp) if resp.ok => {}
+                    Some(resp) => {
+                        // The replica is elsewhere in the stream
+                        let reason = resp.data.unwrap_or_default();
+                        info!("Replica {} refused the stream ({}), syncing all the data", addr, reason);
+                        offset = None;
+                        continue 'sync;
+                    }
+                    None => break 'sync,
+                }
+                // An empty stream once in a while tells the replica the master is still there
+                let stream = tokio::time::timeout(Duration::from_secs(REPL_PING_SECS), queue.next()).await;
+                let stream = match stream {
+                    std::result::Result::Ok(Some(stream)) => stream,
+                    std::result::Result::Ok(None) => break 'sync,
+                    Err(_) => String::new(),
+                };
+                let len = stream.len();
+                let args = vec![id.clone().into(), at.to_string().into(), stream.into()];
+                resp = send(RedisCommand::ReplStream, args).await;
+                if resp.as_ref().is_some_and(|resp| resp.ok) {
+                    queue.sent(len);
+                    at += len as u64;
+                }
+            }
+        }
+        self.repl.lock().detach(&client);
+        self.client_addrs.lock().await.remove(&client);
+    }
+    /// Take a chunk of the data of a full sync from the master. The data replaces the current one
+    /// once the last chunk is received, if it is whole; a chunk is refused when it does not follow
+    /// the ones received, so that the master starts the transfer over
+    async fn receive_data(&self, req: SyncChunkRequest) -> anyhow::Result<GetItemResponse> {
+        let refused = |reason: String| GetItemResponse {
+            ok: false,
+            data: Some(reason.into()),
+            items: None,
+        };
+        self.master_seen.store(unix_time(), Ordering::Relaxed);
+        let data = {
+            let mut transfer = self.transfer.lock();
+            if let Err(reason) = transfer.receive(&req.transfer_id, req.offset as u64, &req.data) {
+                return Ok(refused(reason));
+            }
+            let Some(end) = &req.end else {
+                return Ok(GetItemResponse {
+                    ok: true,
+                    data: None,
+                    items: None,
+                });
+            };
+            match transfer.finish(end.length as u64, end.checksum as u32) {
+                std::result::Result::Ok(data) => data,
+                Err(reason) => return Ok(refused(reason)),
+            }
+        };
+        let end = req.end.unwrap();
+        let length = data.len();
+        {
+            let mut redis = REDIS.lock().await;
+            redis.deserialize(data)?;
+            // The data now follows the stream of the master
+            self.repl.lock().reset(end.repl_id.to_string(), end.repl_offset as u64);
+        }
+        info!("Loaded {} bytes of data from the master", length);
+        // The AOF doesn't hold the data received: a snapshot does, which the records
+        // streamed from now on follow, so that a restart resumes from there. A save under way
+        // holds the data from before, so the data received is saved once it is over
+        let saved = loop {
+            match self.save(false).await {
+                Err(e) if e.to_string() == SAVE_BUSY => {
+                    tokio::time::sleep(Duration::from_millis(SAVE_WAIT_MS)).await;
+                }
+                saved => break saved,
+            }
+        };
+        if let Err(e) = saved {
+            tracing::error!("Failed to save the data from the master: {}", e);
+            // Synced again, rather than followed with a stream a restart would replay on other data
+            return Ok(refused(format!("data not saved: {e}")));
+        }
+        Ok(GetItemResponse {
+            ok: true,
+            data: None,
+            items: None,
+        })
+    }
+    /// Writes are refused while AOF can't be written, except the ones from the master
+    fn check_aof(&self, req: &GetItemRequest) -> anyhow::Result<()> {
+        if command::is_write(req.cmd) && req.client_id.is_none() {
+            if let Some(e) = self.aof_status.error() {
+                return Err(misconf(e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The data as it was when the copy `handle` started, copied `COPY_STEP_KEYS` at a time
+async fn copy_snapshot(handle: usize) -> redis::Snapshot {
+    loop {
+        if let Some(snapshot) = REDIS.lock().await.copy_step(handle, COPY_STEP_KEYS) {
+            return snapshot;
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+/// The data as it was when the copy `handle` started, serialized for a full sync
+/// without the lock
+async fn copy_data(handle: usize) -> Vec<u8> {
+    let snapshot = copy_snapshot(handle).await;
+    tokio::task::spawn_blocking(move || snapshot.serialize())
+        .await
+        .expect("serializing the data panicked")
+}
+
+/// Send `data`, all the data of a full sync at `at` of the stream `repl_id`, `SYNC_CHUNK_BYTES`
+/// at a time, the last chunk with its length and checksum.
+/// return: the answer to the last chunk, or to the first one refused; None once the replica
+/// is dropped
+async fn send_data(
+    replica: &volo_gen::volo::redis::ItemServiceClient,
+    addr: SocketAddr,
+    data: Vec<u8>,
+    repl_id: &str,
+    at: u64,
+) -> Option<GetItemResponse> {
+    let transfer_id: FastStr = Uuid::new_v4().to_string().into();
+    let length = data.len();
+    let checksum = snapshot::crc32(&data);
+    let data = pilota::Bytes::from(data);
+    let mut offset = 0;
+    loop {
+        let next = (offset + SYNC_CHUNK_BYTES).min(length);
+        let end = (next == length).then(|| SyncEnd {
+            length: length as i64,
+            checksum: checksum as i64,
+            repl_id: repl_id.to_string().into(),
+            repl_offset: at as i64,
+        });
+        let last = end.is_some();
+        let request = SyncChunkRequest {
+            transfer_id: transfer_id.clone(),
+            offset: offset as i64,
+            data: data.slice(offset..next),
+            end,
+        };
+        let resp = call_replica(addr, || replica.sync_chunk(request.clone())).await?;
+        if !resp.ok || last {
+            return Some(resp);
+        }
+        offset = next;
+    }
+}
+
+/// Call the replica at `addr`, again while it can't be reached, as it may be restarting.
+/// return: None once given up on, the replica being dropped
+async fn call_replica<F, E>(addr: SocketAddr, call: impl Fn() -> F) -> Option<GetItemResponse>
+where
+    F: std::future::Future<Output = Result<GetItemResponse, E>>,
+    E: std::fmt::Display,
+{
+    for attempt in 1..=REPLICA_ATTEMPTS {
+        match call().await {
+            std::result::Result::Ok(resp) => {
+                return Some(resp);
+            }
+            Err(e) if attempt == REPLICA_ATTEMPTS => {
+                info!("Replica {} dropped: {}", addr, e);
+            }
+            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+    None
+}
+
+fn misconf(e: String) -> anyhow::Error {
+    anyhow!("MISCONF Errors writing to the AOF file: {}", e)
 }
 
 #[derive(Clone)]
@@ -250,14 +825,6 @@ impl<Cx, Req, S> volo::Service<Cx, Req>
     }
 }
 
-fn now() -> Timestamp {
-    let current_timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Failed to get current timestamp");
-
-    current_timestamp.as_millis()
-}
-
 pub struct TimedLayer;
 
 impl<S> volo::Layer<S> for TimedLayer {
@@ -275,6 +842,7 @@ impl S {
         volo_gen::volo::redis::GetItemResponse,
         ::volo_thrift::AnyhowError
     > {
+        self.check_aof(&_req)?;
         match _req.cmd {
             RedisCommand::Ping => {
                 if let Some(arg) = _req.args {
@@ -282,11 +850,13 @@ impl S {
                     Ok(GetItemResponse {
                         ok: true,
                         data: Some(ans),
+                        items: None,
                     })
                 } else {
                     Ok(GetItemResponse {
                         ok: true,
                         data: Some("pong".into()),
+                        items: None,
                     })
                 }
             }
@@ -302,6 +872,7 @@ impl S {
                         return Ok(GetItemResponse {
                             ok: true,
                             data: Some("OK".into()),
+                            items: None,
                         });
                     } else {
                         return Err(anyhow!("Transaction not found"));
@@ -314,19 +885,52 @@ impl S {
                 if arg.len() != 1 {
                     return Err(anyhow!("Invalid arguments count: {} (expected 1)", arg.len()));
                 }
-                if let Some(value) = REDIS.lock().await.get(arg[0].as_ref()) {
+                let (value, effects) = {
+                    let mut redis = lock_db(_req.db).await?;
+                    (redis.get(arg[0].as_ref()), self.take_effects(&mut redis).await)
+                };
+                // A DEL if the key was found expired
+                let _ = self.propagate(effects).await;
+                if let Some(value) = value? {
                     Ok(GetItemResponse {
                         ok: true,
                         data: Some(value.into()),
+                        items: None,
                     })
                 } else {
                     Ok(GetItemResponse {
                         ok: false,
                         data: None,
+                        items: None,
                     })
                 }
             }
-            RedisCommand::Set => {
+            RedisCommand::Set | RedisCommand::SetNx | RedisCommand::SetEx | RedisCommand::PSetEx => {
+                let cmd = _req.cmd;
+                let mut _req = _req;
+                // SETNX, SETEX and PSETEX are SET with the matching options
+                if cmd != RedisCommand::Set {
+                    let arg = _req.args.take().unwrap_or_default();
+                    _req.args = Some(match cmd {
+                        RedisCommand::SetNx if arg.len() == 2 => {
+                            vec![arg[0].clone(), arg[1].clone(), "NX".into()]
+                        }
+                        RedisCommand::SetEx | RedisCommand::PSetEx if arg.len() == 3 => {
+                            let unit = if cmd == RedisCommand::SetEx { "EX" } else { "PX" };
+                            vec![arg[0].clone(), arg[2].clone(), unit.into(), arg[1].clone()]
+                        }
+                        _ => {
+                            return Err(
+                                anyhow!(
+                                    "Invalid arguments count: {} for `{}`",
+                                    arg.len(),
+                                    command::command_name(cmd)
+                                )
+                            );
+                        }
+                    });
+                    _req.cmd = RedisCommand::Set;
+                }
                 let set_transaction_id = _req.transaction_id.clone();
                 if let Some(t_id) = set_transaction_id {
                     let mut transactions = TRANSACTION_HASHMAP.lock().await;
@@ -337,6 +941,7 @@ impl S {
                         return Ok(GetItemResponse {
                             ok: true,
                             data: Some("OK".into()),
+                            items: None,
                         });
                     } else {
                         return Err(anyhow!("Transaction not found"));
@@ -364,54 +969,18 @@ impl S {
                     return Err(anyhow!("Invalid arguments count: {} (expected >=2)", arg.len()));
                 }
                 let (key, value) = (&arg[0], &arg[1]);
-                let mut milliseconds = 0;
-                if let Some(exp_type) = arg.get(2) {
-                    if let Some(exp_num) = arg.get(3) {
-                        let exp_after = exp_num.parse::<u128>()?;
-
-                        match exp_type.to_lowercase().as_ref() {
-                            "ex" => {
-                                milliseconds = exp_after * 1000;
-                            }
-                            "px" => {
-                                milliseconds = exp_after;
-                            }
-                            _ => {
-                                return Err(anyhow!("Unsupported time type `{exp_type}`"));
-                            }
-                        }
-                    } else {
-                        return Err(anyhow!("Duration number not provided."));
-                    }
-                }
-                let command_str = format!("SET {} {} {}\n", key, value, if milliseconds != 0 {
-                    now() + milliseconds
-                } else {
-                    0u128
-                });
-                self.send_message(command_str).await;
-                println!("xxx");
-                REDIS.lock().await.set_after(key.as_ref(), value.as_ref(), milliseconds);
-                // propagate to slaves
-                // no need to be master!
-                let caddr = self.client_addrs.lock().await;
-                println!("propagate to {} clients", caddr.len());
-                for (_, cliaddr) in (*caddr).iter() {
-                    println!("set... to {}.", cliaddr.clone().to_string());
-                    let _resp = get_client(cliaddr.clone()).get_item(
-                        volo_gen::volo::redis::GetItemRequest {
-                            cmd: RedisCommand::Set,
-                            args: Some(arg.clone()),
-                            client_id: Some((*self.uuid.lock().await).to_string().into()), // Not Forwarded
-                            transaction_id: _req.transaction_id.clone(), // Forwarded
-                        }
-                    ).await;
-                }
-
-                Ok(GetItemResponse {
-                    ok: true,
-                    data: Some("OK".into()),
-                })
+                let options = command::parse_set_options(&arg[2..])?;
+                let (reply, written, effects) = {
+                    let mut redis = lock_db(_req.db).await?;
+                    let (reply, written) = command::set(&mut redis, key, value, &options)?;
+                    (reply, written, self.take_effects(&mut redis).await)
+                };
+                // The SET is recorded with an absolute deadline, so that slaves agree
+                self.propagate(effects).await?;
+                Ok(match cmd {
+                    RedisCommand::SetNx => command::Reply::Int(written as i64),
+                    _ => reply,
+                }.into())
             }
             RedisCommand::Del => {
                 {
@@ -434,30 +1003,23 @@ impl S {
                 if arg.len() < 1 {
                     return Err(anyhow!("Invalid arguments count: {} (expected >= 1)", arg.len()));
                 }
-                let mut success: u16 = 0;
-                for key in arg.iter() {
-                    success += REDIS.lock().await.del(key.as_ref()) as u16;
-                    let command_str = format!("DEL {:} 0 0\n", key);
-                    self.send_message(command_str).await;
-                }
-                // propagate to slaves
-                // no need to be master!
-                let caddr = self.client_addrs.lock().await;
-                println!("propagate to {} clients", caddr.len());
-                for (_, cliaddr) in (*caddr).iter() {
-                    println!("del... to {}.", cliaddr.clone().to_string());
-                    let _resp = get_client(cliaddr.clone()).get_item(
-                        volo_gen::volo::redis::GetItemRequest {
-                            cmd: RedisCommand::Del,
-                            args: Some(arg.clone()),
-                            client_id: Some((*self.uuid.lock().await).to_string().into()), // Not Forwarded
-                            transaction_id: _req.transaction_id.clone(), // Forwarded
+                let (success, effects) = {
+                    let mut redis = lock_db(_req.db).await?;
+                    let mut success: u16 = 0;
+                    for key in arg.iter() {
+                        if redis.del(key.as_ref()) {
+                            success += 1;
+                            redis.propagate(vec!["DEL".to_string(), key.to_string()]);
                         }
-                    ).await;
-                }
+                    }
+                    (success, self.take_effects(&mut redis).await)
+                };
+                // no need to be master!
+                self.propagate(effects).await?;
                 Ok(GetItemResponse {
                     ok: true,
                     data: Some(success.to_string().into()),
+                    items: None,
                 })
             }
             RedisCommand::Publish => {
@@ -472,6 +1034,7 @@ impl S {
                 Ok(GetItemResponse {
                     ok: true,
                     data: Some(REDIS.lock().await.broadcast(chan, s).to_string().into()),
+                    items: None,
                 })
             }
             RedisCommand::Subscribe => {
@@ -486,6 +1049,7 @@ impl S {
                 Ok(GetItemResponse {
                     ok: true,
                     data: Some(REDIS.lock().await.add_subscriber(channel).to_string().into()),
+                    items: None,
                 })
             }
             RedisCommand::Replicaof => {
@@ -506,37 +1070,19 @@ impl S {
                 let mst_port: Port = arg[1].parse()?;
                 let _mst_addr = SocketAddr::new(mst_host, mst_port);
                 *curr_state = RedisState::SlaveOf(mst_host.clone(), mst_port.clone());
-
-                let self_addr: SocketAddr = SELF_PUB_ADDR.parse().unwrap();
-                // TODO: replace the global client as a performance boost
-                let resp = get_client(SocketAddr::new(mst_host, mst_port)).get_item(
-                    volo_gen::volo::redis::GetItemRequest {
-                        cmd: RedisCommand::Sync,
-                        args: Some(
-                            vec![
-                                self_addr.ip().to_string().into(),
-                                self_addr.port().to_string().into()
-                            ]
-                        ),
-                        client_id: None,
-                        transaction_id: None,
-                    }
-                ).await?;
-
-                if !resp.ok {
-                    return Err(anyhow!("Sync failed: error at server side"));
-                }
-                let uuid = resp.data.unwrap();
-                *self.uuid.lock().await = Uuid::from_str(&uuid.to_string())?;
+                REDIS.lock().await.set_defer_expiry(true);
+                self.sync_with_master(SocketAddr::new(mst_host, mst_port)).await?;
 
                 Ok(GetItemResponse {
                     ok: true,
                     data: Some("OK".into()),
+                    items: None,
                 })
             }
             RedisCommand::Sync => {
-                // Start full sync server-side, return a UUID as an identifier of this client
-                // Should provide 2 ags: client ip, port
+                // Start syncing server-side, return a UUID as an identifier of this client
+                // Should provide 2 ags: client ip, port; then the replication id and offset
+                // the client is at, to be sent only the stream from there if still in the backlog
                 {
                     let mut curr_state = self.state.lock().await;
                     if let RedisState::Single = *curr_state {
@@ -544,44 +1090,82 @@ impl S {
                     }
                 }
                 let arg = _req.args.unwrap();
-                if arg.len() != 2 {
+                if arg.len() != 2 && arg.len() != 4 {
                     return Err(
                         anyhow!(
-                            "Invalid arguments count: {} (expected =2, pub_ip, pub_port)",
+                            "Invalid arguments count: {} (expected =2 or =4, pub_ip, pub_port[, repl_id, repl_offset])",
                             arg.len()
                         )
                     );
                 }
                 let clihost: IpAddr = arg[0].to_string().parse()?;
                 let cliport: Port = arg[1].parse::<u16>()?;
+                let cliaddr = SocketAddr::new(clihost, cliport);
+                let offset = match &arg[2..] {
+                    [id, offset] => {
+                        let offset = offset.parse::<u64>()?;
+                        self.repl.lock().can_continue(id, offset).then_some(offset)
+                    }
+                    _ => None,
+                };
+                match offset {
+                    Some(offset) => info!("Partial resync of {} from offset {}", cliaddr, offset),
+                    None => info!("Full sync of {}", cliaddr),
+                }
 
                 let gen_uuid = Uuid::new_v4();
-                // add this uuid to client list
+                // add this uuid to client list, in place of a previous sync from the same client
                 let mut caddr = self.client_addrs.lock().await;
-                caddr.insert(gen_uuid, SocketAddr::new(clihost, cliport));
-
-                tokio::spawn(async move {
-                    let readonly = REDIS.lock().await;
-                    let data = readonly.serialize(); // HEAVY WORKLOAD
-                    //...When the data is generated:
-                    let _resp = get_client(SocketAddr::new(clihost, cliport)).get_item(
-                        volo_gen::volo::redis::GetItemRequest {
-                            cmd: RedisCommand::SyncGot,
-                            args: Some(vec![unsafe { FastStr::from_vec_u8_unchecked(data) }]),
-                            client_id: None,
-                            transaction_id: None,
-                        }
-                    ).await;
+                caddr.retain(|client, addr| {
+                    let replaced = *addr == cliaddr;
+                    if replaced {
+                        self.repl.lock().detach(client);
+                    }
+                    !replaced
                 });
+                caddr.insert(gen_uuid, cliaddr);
+                drop(caddr);
+
+                let feeder = self.clone();
+                tokio::spawn(async move { feeder.feed_replica(gen_uuid, cliaddr, offset).await });
                 Ok(GetItemResponse {
                     ok: true,
                     data: Some(gen_uuid.to_string().into()), // this UUID will be decoded in Replicaof command at the client side
+                    items: None,
                 })
             }
             RedisCommand::ClusterMeet => { unimplemented!() }
             RedisCommand::ClusterAddSlots => { unimplemented!() }
             RedisCommand::ClusterCreate => { unimplemented!() }
             RedisCommand::Exec => { unimplemented!() }
+            RedisCommand::Save => {
+                self.save(false).await?;
+                Ok(GetItemResponse {
+                    ok: true,
+                    data: Some("OK".into()),
+                    items: None,
+                })
+            }
+            RedisCommand::BgSave => {
+                self.save(true).await?;
+                Ok(GetItemResponse {
+                    ok: true,
+                    data: Some("Background saving started".into()),
+                    items: None,
+                })
+            }
+            RedisCommand::LastSave => {
+                let last_save = self.save_status.last_save.load(Ordering::Relaxed);
+                Ok(command::Reply::Int(last_save as i64).into())
+            }
+            RedisCommand::BgRewriteAof => {
+                self.rewrite_aof().await?;
+                Ok(GetItemResponse {
+                    ok: true,
+                    data: Some("Background append only file rewriting started".into()),
+                    items: None,
+                })
+            }
             RedisCommand::Multi => {
                 //generate a nanoid as transaction id make sure it is not a key in the Transaction hashmap
                 let mut transaction_id = nanoid!(5);
@@ -595,6 +1179,7 @@ impl S {
                 Ok(GetItemResponse {
                     ok: true,
                     data: Some(transaction_id.into()),
+                    items: None,
                 })
             }
             RedisCommand::Watch => {
@@ -610,7 +1195,7 @@ impl S {
                         key_watched.insert(watch_key.clone(), transactions);
                         key_watched.get_mut(&watch_key).unwrap()
                     };
-                    let match_value = self.redis.lock().await.get(watch_key.as_ref());
+                    let match_value = lock_db(_req.db).await?.get(watch_key.as_ref()).ok().flatten();
                     //transaction_watcher is key map the watching transaction id and the value of the key
                     let response = if transaction_watcher.contains_key(&watch_key) {
                         let mut watch_pair = transaction_watcher.get_mut(&watch_key).unwrap();
@@ -622,6 +1207,7 @@ impl S {
                             Ok(GetItemResponse {
                                 ok: true,
                                 data: Some("OK".into()),
+                                items: None,
                             })
                         }
                     } else {
@@ -632,6 +1218,7 @@ impl S {
                         Ok(GetItemResponse {
                             ok: true,
                             data: Some("OK".into()),
+                            items: None,
                         })
                     };
                     response
@@ -639,6 +1226,117 @@ impl S {
                     Err(anyhow!("No arguments given (required)"))
                 }
             }
+            RedisCommand::Incr |
+            RedisCommand::Decr |
+            RedisCommand::IncrBy |
+            RedisCommand::DecrBy |
+            RedisCommand::IncrByFloat |
+            RedisCommand::MGet |
+            RedisCommand::MSet |
+            RedisCommand::MSetNx |
+            RedisCommand::Exists |
+            RedisCommand::Type |
+            RedisCommand::DbSize |
+            RedisCommand::RandomKey |
+            RedisCommand::Keys |
+            RedisCommand::Scan |
+            RedisCommand::Expire |
+            RedisCommand::PExpire |
+            RedisCommand::ExpireAt |
+            RedisCommand::PExpireAt |
+            RedisCommand::Ttl |
+            RedisCommand::PTtl |
+            RedisCommand::ExpireTime |
+            RedisCommand::PExpireTime |
+            RedisCommand::Persist |
+            RedisCommand::Rename |
+            RedisCommand::RenameNx |
+            RedisCommand::Copy |
+            RedisCommand::Move |
+            RedisCommand::Select |
+            RedisCommand::SwapDb |
+            RedisCommand::FlushDb |
+            RedisCommand::FlushAll |
+            RedisCommand::Append |
+            RedisCommand::StrLen |
+            RedisCommand::GetRange |
+            RedisCommand::SetRange |
+            RedisCommand::GetSet |
+            RedisCommand::GetDel |
+            RedisCommand::GetEx |
+            RedisCommand::LPush |
+            RedisCommand::RPush |
+            RedisCommand::LPop |
+            RedisCommand::RPop |
+            RedisCommand::LRange |
+            RedisCommand::LLen |
+            RedisCommand::LIndex |
+            RedisCommand::LSet |
+            RedisCommand::LRem |
+            RedisCommand::LTrim |
+            RedisCommand::HSet |
+            RedisCommand::HGet |
+            RedisCommand::HmGet |
+            RedisCommand::HDel |
+            RedisCommand::HExists |
+            RedisCommand::HLen |
+            RedisCommand::HKeys |
+            RedisCommand::HVals |
+            RedisCommand::HGetAll |
+            RedisCommand::HIncrBy |
+            RedisCommand::SAdd |
+            RedisCommand::SRem |
+            RedisCommand::SIsMember |
+            RedisCommand::SmIsMember |
+            RedisCommand::SCard |
+            RedisCommand::SMembers |
+            RedisCommand::SPop |
+            RedisCommand::SRandMember |
+            RedisCommand::SInter |
+            RedisCommand::SUnion |
+            RedisCommand::SDiff |
+            RedisCommand::SInterStore |
+            RedisCommand::SUnionStore |
+            RedisCommand::SDiffStore |
+            RedisCommand::ZAdd |
+            RedisCommand::ZRem |
+            RedisCommand::ZScore |
+            RedisCommand::ZIncrBy |
+            RedisCommand::ZCard |
+            RedisCommand::ZRank |
+            RedisCommand::ZRevRank |
+            RedisCommand::ZRange |
+            RedisCommand::ZRangeByScore |
+            RedisCommand::ZCount |
+            RedisCommand::ZPopMin |
+            RedisCommand::ZPopMax |
+            RedisCommand::ZRemRangeByScore => {
+                if command::is_write(_req.cmd) {
+                    let curr_state = self.state.lock().await;
+                    if let RedisState::SlaveOf(_, _) = *curr_state {
+                        if _req.client_id.is_none() {
+                            return Err(
+                                anyhow!(
+                                    "{} is forbidden on slave node if no uuid provided.",
+                                    command::command_name(_req.cmd)
+                                )
+                            );
+                        }
+                    }
+                }
+                let args = _req.args.unwrap_or_default();
+                let (reply, effects) = {
+                    let mut redis = lock_db(_req.db).await?;
+                    let reply = command::execute(&mut redis, _req.cmd, &args);
+                    (reply, self.take_effects(&mut redis).await)
+                };
+                let synced = self.propagate(effects).await;
+                // Reads only record the DEL of a key found expired
+                if command::is_write(_req.cmd) {
+                    synced?;
+                }
+                Ok(reply?.into())
+            }
             // Internal commands, you can seen as remote interupts
             RedisCommand::Fetch => {
                 if _req.args.is_none() {
@@ -657,21 +1355,69 @@ impl S {
                     } else {
                         None
                     },
+                    items: None,
                 })
             }
-            RedisCommand::SyncGot => {
-                if _req.args.is_none() {
-                    return Err(anyhow!("Failed to get deserialized data."));
+            RedisCommand::ReplStream => {
+                // The replication stream of the master, from the offset the data is at
+                let payload = _req.args.unwrap_or_default();
+                let [repl_id, repl_offset, stream] = &payload[..] else {
+                    return Err(anyhow!("Invalid arguments count: {} (expected =3)", payload.len()));
+                };
+                let repl_offset = repl_offset.parse::<u64>()?;
+                let mut redis = REDIS.lock().await;
+                {
+                    let repl = self.repl.lock();
+                    if !(repl.id == repl_id.as_str() && repl.offset() == repl_offset) {
+                        let reason = format!(
+                            "stream at {} of {}, the data is at {} of {}",
+                            repl_offset,
+                            repl_id,
+                            repl.offset(),
+                            repl.id
+                        );
+                        return Ok(GetItemResponse {
+                            ok: false,
+                            data: Some(reason.into()),
+                            items: None,
+                        });
+                    }
                 }
-                let payload = _req.args.unwrap();
-                if payload.len() != 1 {
-                    return Err(anyhow!("Illegal deserial data format."));
+                self.master_seen.store(unix_time(), Ordering::Relaxed);
+                if stream.is_empty() {
+                    // Only tells the master is there
+                    return Ok(GetItemResponse {
+                        ok: true,
+                        data: Some("OK".into()),
+                        items: None,
+                    });
+                }
+                // Read whole before any of it is applied: a batch is applied once or not at all,
+                // as the offset it moves the data to
+                let mut reader = aof::AofReader::new(stream.as_bytes());
+                let mut records = Vec::new();
+                while let Some(record) = reader.next_record()? {
+                    records.push(record);
+                }
+                // Batches of the master start in database 0, whichever one the last request
+                // here selected, which is selected again after
+                let selected = redis.selected_db();
+                redis.select(0)?;
+                for record in &records {
+                    if let Err(e) = command::replay(&mut redis, record) {
+                        tracing::warn!("Invalid command from master: {} ({})", record.join(" "), e);
+                    }
                 }
-                REDIS.lock().await.deserialize(payload[0].to_owned().into_bytes().to_vec());
-                info!("Serialize success!!!");
+                redis.select(selected)?;
+                self.save_status.dirty.fetch_add(records.len() as u64, Ordering::Relaxed);
+                // Logged as received, so that AOF and stream stay byte for byte the same
+                self.feed_replicas(stream);
+                self.send_message(AofMessage::Append(stream.to_string(), None)).await;
+                drop(redis);
                 Ok(GetItemResponse {
                     ok: true,
-                    data: None,
+                    data: Some("OK".into()),
+                    items: None,
                 })
             }
         }
@@ -705,11 +1451,11 @@ impl volo_gen::volo::redis::ItemService for S {
             {
                 let ctrl_c = CTRL_C.lock().await;
                 if *ctrl_c {
-                    self.send_message("SHUTDOWN".to_string()).await;
+                    self.send_message(AofMessage::Shutdown).await;
                 }
             }
             if *shared_data.lock().await {
-                self.send_message("SHUTDOWN".to_string()).await;
+                self.send_message(AofMessage::Shutdown).await;
                 let mut ctrl_c = CTRL_C.lock().await;
                 *ctrl_c = true;
                 info!("New requests rejected whe shutting down.");
@@ -751,7 +1497,7 @@ impl volo_gen::volo::redis::ItemService for S {
                     if transaction_watcher.contains_key(key) {
                         let mut watch_pair = transaction_watcher.get_mut(&key.clone()).unwrap();
                         let old_value = watch_pair.get(&transaction_id.to_string()).unwrap();
-                        let new_value = self.redis.lock().await.get(key.as_ref());
+                        let new_value = lock_db(_req.db).await?.get(key.as_ref()).ok().flatten();
                         if old_value.to_owned() != new_value {
                             transaction.is_wrong = true;
                             break;
@@ -777,47 +1523,19 @@ impl volo_gen::volo::redis::ItemService for S {
                                         )
                                     } else {
                                         let (key, value) = (&arg[0], &arg[1]);
-                                        let mut milliseconds = 0;
-                                        if let Some(exp_type) = arg.get(2) {
-                                            if let Some(exp_num) = arg.get(3) {
-                                                let exp_after = exp_num.parse::<u128>()?;
-
-                                                match exp_type.to_lowercase().as_ref() {
-                                                    "ex" => {
-                                                        milliseconds = exp_after * 1000;
-                                                    }
-                                                    "px" => {
-                                                        milliseconds = exp_after;
-                                                    }
-                                                    _ => {
-                                                        return Err(
-                                                            anyhow!(
-                                                                "Unsupported time type `{exp_type}`"
-                                                            )
-                                                        );
-                                                    }
-                                                }
-                                            } else {
-                                                return Err(
-                                                    anyhow!("Duration number not provided.")
-                                                );
-                                            }
-                                        }
-                                        let command_str = format!("SET {} {} {}\n", key, value, if
-                                            milliseconds != 0
-                                        {
-                                            now() + milliseconds
-                                        } else {
-                                            0u128
-                                        });
-                                        self.send_message(command_str).await;
-                                        self.redis
-                                            .lock().await
-                                            .set_after(key.as_ref(), value.as_ref(), milliseconds);
-                                        Ok(GetItemResponse {
-                                            ok: true,
-                                            data: Some("OK".into()),
-                                        })
+                                        let options = command::parse_set_options(&arg[2..])?;
+                                        self.check_aof(command)?;
+                                        let (reply, effects) = {
+                                            let mut redis = lock_db(command.db).await?;
+                                            let (reply, _) = command::set(
+                                                &mut redis,
+                                                key,
+                                                value,
+                                                &options
+                                            )?;
+                                            (reply, self.take_effects(&mut redis).await)
+                                        };
+                                        self.propagate(effects).await.map(|()| reply.into())
                                     }
                                 } else {
                                     Err(anyhow!("No arguments given (required)"))
@@ -835,18 +1553,19 @@ impl volo_gen::volo::redis::ItemService for S {
                                         )
                                     } else {
                                         if
-                                            let Some(value) = self.redis
-                                                .lock().await
-                                                .get(arg[0].as_ref())
+                                            let Some(value) = lock_db(command.db).await?
+                                                .get(arg[0].as_ref())?
                                         {
                                             Ok(GetItemResponse {
                                                 ok: true,
                                                 data: Some(value.into()),
+                                                items: None,
                                             })
                                         } else {
                                             Ok(GetItemResponse {
                                                 ok: false,
                                                 data: None,
+                                                items: None,
                                             })
                                         }
                                     }
@@ -873,11 +1592,11 @@ impl volo_gen::volo::redis::ItemService for S {
             {
                 let ctrl_c = CTRL_C.lock().await;
                 if *ctrl_c {
-                    self.send_message("SHUTDOWN".to_string()).await;
+                    self.send_message(AofMessage::Shutdown).await;
                 }
             }
             if *shared_data.lock().await {
-                self.send_message("SHUTDOWN".to_string()).await;
+                self.send_message(AofMessage::Shutdown).await;
                 let mut ctrl_c = CTRL_C.lock().await;
                 *ctrl_c = true;
                 info!("New requests rejected whe shutting down.");
@@ -886,4 +1605,10 @@ impl volo_gen::volo::redis::ItemService for S {
         let _ = child.abort();
         return response;
     }
+    async fn sync_chunk(
+        &self,
+        _req: SyncChunkRequest
+    ) -> ::core::result::Result<GetItemResponse, ::volo_thrift::AnyhowError> {
+        Ok(self.receive_data(_req).await?)
+    }
 }
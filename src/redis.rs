@@ -1,48 +1,440 @@
+use crate::cmdargs::EvictionPolicy;
+use crate::glob::glob_match;
+use crate::zset::{SortedSet, ZAddFlags};
+use anyhow::{anyhow, Result};
+use rand::seq::IteratorRandom;
+use rand::Rng;
 use rmp_serde::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
 use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::time::Duration;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
     time::{SystemTime, UNIX_EPOCH},
 };
 
 pub type Timestamp = u128;
 pub type RcvHandle = usize;
+/// A write command in its canonical form: command name followed by its arguments
+pub type Record = Vec<String>;
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Bytes of an entry besides its key and value: map slot, SCAN index node and metadata
+const ENTRY_OVERHEAD: usize = 96;
+/// Bytes of an element of a collection besides its content
+const ELEMENT_OVERHEAD: usize = 16;
+/// Elements looked at to estimate the size of a collection
+const SIZE_SAMPLES: usize = 5;
+
+/// LFU counter of new keys, so that they are not the first ones evicted
+const LFU_INIT: u8 = 5;
+/// The higher, the more accesses it takes to increment the LFU counter
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// The LFU counter is decremented for each period without access
+const LFU_DECAY_MS: Timestamp = 60_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Str(String),
+    List(VecDeque<String>),
+    Hash(HashMap<String, String>),
+    Set(HashSet<String>),
+    ZSet(SortedSet),
+}
+
+impl Value {
+    /// Name of the type, as replied by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    /// Estimated bytes used. Collections are sized from a few of their elements,
+    /// as MEMORY USAGE does
+    fn mem_usage(&self) -> usize {
+        fn sampled(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+            let (n, total) = sizes
+                .take(SIZE_SAMPLES)
+                .fold((0, 0), |(n, total), size| (n + 1, total + size));
+            match n {
+                0 => 0,
+                n => total * len / n,
+            }
+        }
+        match self {
+            Value::Str(s) => s.len(),
+            Value::List(list) => {
+                sampled(list.len(), list.iter().map(|e| e.len() + ELEMENT_OVERHEAD))
+            }
+            Value::Hash(hash) => sampled(
+                hash.len(),
+                hash.iter()
+                    .map(|(f, v)| f.len() + v.len() + 2 * ELEMENT_OVERHEAD),
+            ),
+            Value::Set(set) => sampled(set.len(), set.iter().map(|e| e.len() + ELEMENT_OVERHEAD)),
+            Value::ZSet(zset) => sampled(
+                zset.len(),
+                zset.range_by_rank(0, SIZE_SAMPLES)
+                    .into_iter()
+                    .map(|(member, _)| member.len() + 8 + 2 * ELEMENT_OVERHEAD),
+            ),
+        }
+    }
+}
+
+/// Estimated bytes used by an entry
+pub fn entry_size(key: &str, value: &Value) -> usize {
+    // The key is held by both the map and the SCAN index
+    ENTRY_OVERHEAD + 2 * key.len() + value.mem_usage()
+}
+
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TimedValue {
-    pub value: String,
+    pub value: Value,
 
     /// None = never expire, otherwise a timestamp
     pub expired_at: Option<Timestamp>,
+
+    /// Estimated bytes used by the entry, counted in `StoredKV::used`
+    #[serde(skip)]
+    size: usize,
+
+    /// Last access, for LRU eviction
+    #[serde(skip)]
+    accessed: Timestamp,
+
+    /// Logarithmic access counter, for LFU eviction
+    #[serde(skip)]
+    freq: u8,
+}
+
+impl TimedValue {
+    fn new(value: Value, expired_at: Option<Timestamp>) -> Self {
+        Self {
+            value,
+            expired_at,
+            size: 0,
+            accessed: Redis::now(),
+            freq: LFU_INIT,
+        }
+    }
+
+    /// Record an access: the more accesses, the less likely the counter is incremented,
+    /// so that it spans a large range of access frequencies
+    fn touch(&mut self) {
+        let now = Redis::now();
+        self.freq = self.lfu(now);
+        let base = self.freq.saturating_sub(LFU_INIT) as f64;
+        if self.freq < u8::MAX && rand::random::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            self.freq += 1;
+        }
+        self.accessed = now;
+    }
+
+    /// The LFU counter, decayed since the last access
+    fn lfu(&self, now: Timestamp) -> u8 {
+        let periods = now.saturating_sub(self.accessed) / LFU_DECAY_MS;
+        self.freq
+            .saturating_sub(periods.min(u8::MAX as Timestamp) as u8)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// One numbered database
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct StoredKV {
     pub data: HashMap<String, TimedValue>,
+
+    /// Expiry deadlines, earliest first, for the active expiry cycle.
+    /// Entries are not removed when a key is deleted or its expiry changes,
+    /// they are checked against the key when popped
+    #[serde(skip)]
+    deadlines: BinaryHeap<Reverse<(Timestamp, String)>>,
+
+    /// Every key ordered by its hash, which is what SCAN cursors point into
+    #[serde(skip)]
+    scan_index: BTreeSet<(u64, String)>,
+
+    /// Estimated bytes used by the entries
+    #[serde(skip)]
+    used: usize,
+}
+
+impl StoredKV {
+    /// Rebuild the indexes of `data`, after it has been loaded
+    fn reindex(&mut self) {
+        self.deadlines = self
+            .data
+            .iter()
+            .filter_map(|(key, tv)| Some(Reverse((tv.expired_at?, key.clone()))))
+            .collect();
+        self.scan_index = self
+            .data
+            .keys()
+            .map(|key| (key_hash(key), key.clone()))
+            .collect();
+    }
+
+    /// Size the entries of `data`, after it has been loaded
+    fn measure(&mut self) {
+        let now = Redis::now();
+        self.used = 0;
+        for (key, tv) in self.data.iter_mut() {
+            tv.size = entry_size(key, &tv.value);
+            tv.accessed = now;
+            tv.freq = LFU_INIT;
+            self.used += tv.size;
+        }
+    }
+
+    /// The first key from a random point of the hash order, wrapping around
+    fn random_key(&self) -> Option<&String> {
+        let from = (rand::thread_rng().gen::<u64>(), String::new());
+        let (_, key) = self
+            .scan_index
+            .range(from..)
+            .next()
+            .or_else(|| self.scan_index.first())?;
+        Some(key)
+    }
+}
+
+/// Elements of a collection per record of an AOF rewrite
+const ITEMS_PER_RECORD: usize = 64;
+
+/// A key with its value and expiry, as saved in snapshots
+pub type Entry = (String, Value, Option<Timestamp>);
+
+/// A copy of the data at some point, to be written out in the background
+pub struct Snapshot {
+    dbs: Vec<StoredKV>,
+}
+
+impl Snapshot {
+    /// The entries of each database
+    pub fn into_databases(self) -> Vec<Vec<Entry>> {
+        self.dbs
+            .into_iter()
+            .map(|kvs| {
+                kvs.data
+                    .into_iter()
+                    .map(|(key, tv)| (key, tv.value, tv.expired_at))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The fewest records rebuilding the data, each database after a SELECT.
+    /// They end in database 0, where the records appended later start
+    pub fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let now = Redis::now();
+        let select = |db: usize| vec!["SELECT".to_string(), db.to_string()];
+        self.dbs
+            .iter()
+            .enumerate()
+            .filter(|(_, kvs)| !kvs.data.is_empty())
+            .flat_map(move |(db, kvs)| {
+                let live = kvs
+                    .data
+                    .iter()
+                    .filter(move |(_, tv)| !tv.expired_at.is_some_and(|at| at < now));
+                std::iter::once(select(db)).chain(live.flat_map(|(key, tv)| key_records(key, tv)))
+            })
+            .chain(std::iter::once(select(0)))
+    }
+
+    /// Serialize the data, as `Redis::deserialize` reads it
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.dbs.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        buf
+    }
+}
+
+/// A copy of the data at some point, made a few keys at a time while writes go on:
+/// a key the copy has not reached yet is copied before it is written
+struct SnapshotCopy {
+    dbs: Vec<HashMap<String, TimedValue>>,
+    /// Database being copied, and the last key of it copied in the SCAN order
+    db: usize,
+    last: Option<(u64, String)>,
+    /// Keys not reached yet which were copied early, or were absent at that point
+    settled: HashSet<(usize, String)>,
+}
+
+impl SnapshotCopy {
+    fn new(databases: usize) -> Self {
+        Self {
+            dbs: (0..databases).map(|_| HashMap::new()).collect(),
+            db: 0,
+            last: None,
+            settled: HashSet::new(),
+        }
+    }
+
+    /// Whether the copy went past `key` of the database `db`
+    fn passed(&self, db: usize, key: &str) -> bool {
+        match db.cmp(&self.db) {
+            Ordering::Less => true,
+            Ordering::Greater => false,
+            Ordering::Equal => self
+                .last
+                .as_ref()
+                .is_some_and(|(hash, last)| (key_hash(key), key) <= (*hash, last.as_str())),
+        }
+    }
+
+    /// Copy `tv`, the entry of `key` before a write, unless the copy already decided on the key
+    fn keep(&mut self, db: usize, key: &str, tv: Option<&TimedValue>) {
+        if self.passed(db, key) || !self.settled.insert((db, key.to_string())) {
+            return;
+        }
+        if let Some(tv) = tv {
+            self.dbs[db].insert(key.to_string(), tv.clone());
+        }
+    }
+
+    /// Copy up to `limit` more keys of `dbs`.
+    /// return: whether the copy is complete
+    fn step(&mut self, dbs: &[StoredKV], mut limit: usize) -> bool {
+        while self.db < dbs.len() {
+            let kvs = &dbs[self.db];
+            let from = match &self.last {
+                Some(last) => Bound::Excluded(last.clone()),
+                None => Bound::Unbounded,
+            };
+            for (hash, key) in kvs.scan_index.range((from, Bound::Unbounded)) {
+                if limit == 0 {
+                    return false;
+                }
+                limit -= 1;
+                self.last = Some((*hash, key.clone()));
+                let settled = !self.settled.is_empty() && self.settled.remove(&(self.db, key.clone()));
+                if !settled {
+                    self.dbs[self.db].insert(key.clone(), kvs.data[key].clone());
+                }
+            }
+            self.db += 1;
+            self.last = None;
+        }
+        true
+    }
+}
+
+/// Records creating `key` as it is
+fn key_records(key: &str, tv: &TimedValue) -> Vec<Record> {
+    let chunked = |cmd: &str, items: Vec<Vec<String>>| -> Vec<Record> {
+        items
+            .chunks(ITEMS_PER_RECORD)
+            .map(|chunk| {
+                [cmd.to_string(), key.to_string()]
+                    .into_iter()
+                    .chain(chunk.concat())
+                    .collect()
+            })
+            .collect()
+    };
+    let mut records = match &tv.value {
+        Value::Str(s) => vec![vec!["SET".to_string(), key.to_string(), s.clone()]],
+        Value::List(list) => chunked("RPUSH", list.iter().map(|e| vec![e.clone()]).collect()),
+        Value::Hash(hash) => chunked(
+            "HSET",
+            hash.iter().map(|(f, v)| vec![f.clone(), v.clone()]).collect(),
+        ),
+        Value::Set(set) => chunked("SADD", set.iter().map(|e| vec![e.clone()]).collect()),
+        Value::ZSet(zset) => chunked(
+            "ZADD",
+            zset.range_by_rank(0, zset.len())
+                .into_iter()
+                .map(|(member, score)| vec![score.to_string(), member])
+                .collect(),
+        ),
+    };
+    if let Some(at) = tv.expired_at {
+        records.push(vec!["PEXPIREAT".to_string(), key.to_string(), at.to_string()]);
+    }
+    records
 }
 
 pub struct Redis {
-    /// Key-Value
-    kvs: StoredKV,
+    /// Key-Value, one per database
+    dbs: Vec<StoredKV>,
+    /// Database the commands run against
+    db: usize,
 
     /// Channel name-Senders
     channels: HashMap<String, Vec<Sender<String>>>,
     rcv: HashMap<RcvHandle, Receiver<String>>,
+
+    /// Writes done since the last `take_effects`, with the database they were done in,
+    /// to be appended to AOF and sent to slaves
+    effects: Vec<(usize, Record)>,
+
+    /// Keys are not expired by the local clock nor evicted, only removed by DELs: on slaves,
+    /// which wait for the DELs of their master, and while replaying the AOF
+    defer_expiry: bool,
+    /// Commands are replayed, from the AOF or the master: meanwhile the expired keys awaiting
+    /// their DEL are seen as they were when the commands first ran, not as absent
+    replaying: bool,
+
+    /// Bytes the entries may use before keys are evicted, 0 for no limit
+    maxmemory: usize,
+    policy: EvictionPolicy,
+    /// Keys sampled per database to pick one to evict
+    samples: usize,
+
+    /// Copies under way, by handle
+    copies: HashMap<usize, SnapshotCopy>,
+    next_copy: usize,
+}
+
+/// Position of a key in the SCAN order, the same for the whole life of the process
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl Redis {
-    pub fn new() -> Self {
+    pub fn new(databases: usize) -> Self {
         Self {
-            kvs: StoredKV {
-                data: HashMap::new(),
-            },
+            dbs: (0..databases.max(1)).map(|_| StoredKV::default()).collect(),
+            db: 0,
             channels: HashMap::new(),
             rcv: HashMap::new(),
+            effects: Vec::new(),
+            defer_expiry: false,
+            replaying: false,
+            maxmemory: 0,
+            policy: EvictionPolicy::NoEviction,
+            samples: 5,
+            copies: HashMap::new(),
+            next_copy: 0,
         }
     }
-    fn now() -> Timestamp {
+
+    pub fn set_maxmemory(&mut self, maxmemory: usize, policy: EvictionPolicy, samples: usize) {
+        self.maxmemory = maxmemory;
+        self.policy = policy;
+        self.samples = samples.max(1);
+    }
+    pub fn now() -> Timestamp {
         let current_timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Failed to get current timestamp");
@@ -58,17 +450,406 @@ impl Redis {
         }
     }
 
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        if let Some(tv) = self.kvs.data.get(key) {
-            if Self::expired(tv.expired_at) {
-                self.kvs.data.remove(key);
-                None
-            } else {
-                Some(tv.value.clone())
+    /// Whether `key` exists and is not expired, an expired one is removed on the way.
+    /// Unlike `entry`, this is not an access of the key
+    fn live(&mut self, key: &str) -> bool {
+        let Some(tv) = self.dbs[self.db].data.get(key) else {
+            return false;
+        };
+        if !Self::expired(tv.expired_at) {
+            return true;
+        }
+        if self.defer_expiry {
+            // Reads as absent, but is only removed by its DEL
+            return self.replaying;
+        }
+        self.remove_entry(key);
+        self.propagate(vec!["DEL".to_string(), key.to_string()]);
+        false
+    }
+
+    /// Live (not expired) entry of `key`, expired ones are removed on the way
+    fn entry(&mut self, key: &str) -> Option<&mut TimedValue> {
+        if !self.live(key) {
+            return None;
+        }
+        // The entry may be modified by the caller
+        self.before_write(key);
+        let tv = self.dbs[self.db].data.get_mut(key)?;
+        tv.touch();
+        Some(tv)
+    }
+
+    fn insert_entry(&mut self, key: &str, mut tv: TimedValue) {
+        self.before_write(key);
+        tv.size = entry_size(key, &tv.value);
+        let kvs = &mut self.dbs[self.db];
+        kvs.used += tv.size;
+        match kvs.data.insert(key.to_string(), tv) {
+            Some(old) => kvs.used -= old.size,
+            None => {
+                kvs.scan_index.insert((key_hash(key), key.to_string()));
+            }
+        }
+    }
+
+    fn remove_entry(&mut self, key: &str) -> Option<TimedValue> {
+        self.before_write(key);
+        let kvs = &mut self.dbs[self.db];
+        let tv = kvs.data.remove(key)?;
+        kvs.scan_index.remove(&(key_hash(key), key.to_string()));
+        kvs.used -= tv.size;
+        Some(tv)
+    }
+
+    /// Size `key` again, after its value was modified in place
+    pub fn account(&mut self, key: &str) {
+        let kvs = &mut self.dbs[self.db];
+        if let Some(tv) = kvs.data.get_mut(key) {
+            let size = entry_size(key, &tv.value);
+            kvs.used = kvs.used - tv.size + size;
+            tv.size = size;
+        }
+    }
+
+    /// Estimated bytes used by the entries of all the databases
+    pub fn used_memory(&self) -> usize {
+        self.dbs.iter().map(|kvs| kvs.used).sum()
+    }
+
+    /// Evict keys by the policy until the used memory is within `maxmemory`,
+    /// recording a DEL for each. Nothing is evicted while expiry is deferred.
+    /// return: OOM error if nothing can be evicted while above the limit
+    pub fn free_memory(&mut self) -> Result<()> {
+        if self.maxmemory == 0 || self.defer_expiry {
+            return Ok(());
+        }
+        while self.used_memory() > self.maxmemory {
+            let (db, key) = self.eviction_candidate().ok_or(anyhow!(OOM))?;
+            let selected = self.db;
+            self.db = db;
+            self.remove_entry(&key);
+            self.propagate(vec!["DEL".to_string(), key]);
+            self.db = selected;
+        }
+        Ok(())
+    }
+
+    /// The best key to evict by the policy, among `samples` keys sampled from each database
+    fn eviction_candidate(&mut self) -> Option<(usize, String)> {
+        let volatile = match self.policy {
+            EvictionPolicy::NoEviction => return None,
+            EvictionPolicy::VolatileLru | EvictionPolicy::VolatileTtl => true,
+            _ => false,
+        };
+        let now = Self::now();
+        // The higher the score, the better to evict
+        let mut best: Option<(u128, usize, String)> = None;
+        for db in 0..self.dbs.len() {
+            for key in self.sample_keys(db, volatile) {
+                let tv = &self.dbs[db].data[&key];
+                let idle = now.saturating_sub(tv.accessed);
+                let score = match self.policy {
+                    EvictionPolicy::AllkeysLfu => {
+                        // Least recently used first among the least frequently used
+                        ((u8::MAX - tv.lfu(now)) as u128) << 64 | idle.min(u64::MAX as u128)
+                    }
+                    EvictionPolicy::VolatileTtl => Timestamp::MAX - tv.expired_at.unwrap_or(0),
+                    EvictionPolicy::AllkeysRandom => rand::random::<u64>() as u128,
+                    _ => idle,
+                };
+                if best.as_ref().is_none_or(|(best, _, _)| score > *best) {
+                    best = Some((score, db, key));
+                }
+            }
+        }
+        best.map(|(_, db, key)| (db, key))
+    }
+
+    /// Random keys of the database `db`, only keys with an expiry if `volatile`
+    fn sample_keys(&mut self, db: usize, volatile: bool) -> Vec<String> {
+        let samples = self.samples;
+        let kvs = &mut self.dbs[db];
+        if !volatile {
+            return (0..samples)
+                .filter_map(|_| kvs.random_key().cloned())
+                .collect();
+        }
+        let mut rng = rand::thread_rng();
+        for retry in [false, true] {
+            let heap = kvs.deadlines.as_slice();
+            let keys: Vec<String> = (0..samples)
+                .filter_map(|_| {
+                    if heap.is_empty() {
+                        return None;
+                    }
+                    let Reverse((at, key)) = &heap[rng.gen_range(0..heap.len())];
+                    (kvs.data.get(key)?.expired_at == Some(*at)).then(|| key.clone())
+                })
+                .collect();
+            if !keys.is_empty() || retry {
+                return keys;
+            }
+            // Only outdated deadlines were drawn, drop them and try again
+            kvs.reindex();
+        }
+        Vec::new()
+    }
+
+    /// Register a deadline for the active expiry cycle
+    fn watch_deadline(&mut self, key: &str, expired_at: Timestamp) {
+        self.dbs[self.db]
+            .deadlines
+            .push(Reverse((expired_at, key.to_string())));
+        // Drop the outdated deadlines once they outnumber the keys
+        let kvs = &mut self.dbs[self.db];
+        if kvs.deadlines.len() > 2 * kvs.data.len() + 1024 {
+            kvs.reindex();
+        }
+    }
+
+    /// Remove up to `limit` expired keys, earliest deadline first, recording a DEL for each.
+    /// Does nothing while expiry is deferred.
+    /// return: number of keys removed
+    pub fn expire_cycle(&mut self, limit: usize) -> usize {
+        if self.defer_expiry {
+            return 0;
+        }
+        let now = Self::now();
+        let selected = self.db;
+        let mut removed = 0;
+        for db in 0..self.dbs.len() {
+            self.db = db;
+            while removed < limit {
+                match self.dbs[db].deadlines.peek() {
+                    Some(Reverse((at, _))) if *at < now => {}
+                    _ => break,
+                }
+                let Reverse((at, key)) = self.dbs[db].deadlines.pop().unwrap();
+                // Skip deadlines of keys deleted or given another expiry since
+                if self.dbs[db].data.get(&key).and_then(|tv| tv.expired_at) == Some(at) {
+                    self.remove_entry(&key);
+                    self.propagate(vec!["DEL".to_string(), key]);
+                    removed += 1;
+                }
+            }
+        }
+        self.db = selected;
+        removed
+    }
+
+    /// Whether keys are only removed by DELs instead of being expired.
+    /// return: the previous setting
+    pub fn set_defer_expiry(&mut self, defer_expiry: bool) -> bool {
+        std::mem::replace(&mut self.defer_expiry, defer_expiry)
+    }
+
+    /// Whether the commands run are replayed, see `replaying`.
+    /// return: the previous setting
+    pub fn set_replaying(&mut self, replaying: bool) -> bool {
+        std::mem::replace(&mut self.replaying, replaying)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.entry(key) {
+            Some(TimedValue {
+                value: Value::Str(s),
+                ..
+            }) => Ok(Some(s.clone())),
+            Some(_) => Err(anyhow!(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn exists(&mut self, key: &str) -> bool {
+        self.entry(key).is_some()
+    }
+
+    /// When the live `key` expires, None if it does not exist or never expires
+    pub fn expiry(&mut self, key: &str) -> Option<Timestamp> {
+        self.entry(key)?.expired_at
+    }
+
+    /// Move the value of `from`, with its expiry, to `to`, replacing what `to` held.
+    /// With `nx`, nothing is done if `to` exists.
+    /// return: whether the key was renamed
+    pub fn rename(&mut self, from: &str, to: &str, nx: bool) -> Result<bool> {
+        if self.entry(from).is_none() {
+            return Err(anyhow!("ERR no such key"));
+        }
+        if nx && self.exists(to) {
+            return Ok(false);
+        }
+        if from != to {
+            let tv = self.remove_entry(from).unwrap();
+            self.put_entry(to, tv);
+        }
+        Ok(true)
+    }
+
+    /// Copy the value of `from`, with its expiry, to `to`.
+    /// Without `replace`, nothing is done if `to` exists.
+    /// return: whether the key was copied
+    pub fn copy(&mut self, from: &str, to: &str, to_db: usize, replace: bool) -> bool {
+        let Some(tv) = self.entry(from).cloned() else {
+            return false;
+        };
+        let selected = self.db;
+        if from == to && to_db == selected {
+            return false;
+        }
+        self.db = to_db;
+        let copied = replace || !self.exists(to);
+        if copied {
+            self.put_entry(to, tv);
+        }
+        self.db = selected;
+        copied
+    }
+
+    /// Move `key`, with its expiry, to the database `to_db`, unless it exists there.
+    /// return: whether the key was moved
+    pub fn move_key(&mut self, key: &str, to_db: usize) -> Result<bool> {
+        let selected = self.db;
+        if to_db == selected {
+            return Err(anyhow!("ERR source and destination objects are the same"));
+        }
+        if !self.exists(key) {
+            return Ok(false);
+        }
+        self.db = to_db;
+        let moved = !self.exists(key);
+        if moved {
+            self.db = selected;
+            let tv = self.remove_entry(key).unwrap();
+            self.db = to_db;
+            self.put_entry(key, tv);
+        }
+        self.db = selected;
+        Ok(moved)
+    }
+
+    /// Store an entry taken from another key, keeping its expiry
+    fn put_entry(&mut self, key: &str, tv: TimedValue) {
+        if let Some(at) = tv.expired_at {
+            self.watch_deadline(key, at);
+        }
+        self.insert_entry(key, tv);
+    }
+
+    /// Type name of the value at `key`, None if it does not exist
+    pub fn key_type(&mut self, key: &str) -> Option<&'static str> {
+        Some(self.entry(key)?.value.type_name())
+    }
+
+    /// Run the following commands against the database `db`
+    pub fn select(&mut self, db: usize) -> Result<()> {
+        self.check_db(db)?;
+        self.db = db;
+        Ok(())
+    }
+
+    pub fn selected_db(&self) -> usize {
+        self.db
+    }
+
+    pub fn check_db(&self, db: usize) -> Result<()> {
+        if db >= self.dbs.len() {
+            return Err(anyhow!("ERR DB index is out of range"));
+        }
+        Ok(())
+    }
+
+    pub fn swapdb(&mut self, a: usize, b: usize) -> Result<()> {
+        self.check_db(a)?;
+        self.check_db(b)?;
+        self.finish_copies();
+        self.dbs.swap(a, b);
+        Ok(())
+    }
+
+    /// Remove every key of the selected database.
+    /// With `lazy`, the memory is freed on another thread
+    pub fn flushdb(&mut self, lazy: bool) {
+        self.finish_copies();
+        let old = std::mem::take(&mut self.dbs[self.db]);
+        if lazy {
+            std::thread::spawn(move || drop(old));
+        }
+    }
+
+    /// Remove every key of every database.
+    /// With `lazy`, the memory is freed on another thread
+    pub fn flushall(&mut self, lazy: bool) {
+        self.finish_copies();
+        let old: Vec<StoredKV> = self.dbs.iter_mut().map(std::mem::take).collect();
+        if lazy {
+            std::thread::spawn(move || drop(old));
+        }
+    }
+
+    /// Number of keys, including the expired ones not removed yet
+    pub fn dbsize(&self) -> usize {
+        self.dbs[self.db].data.len()
+    }
+
+    pub fn random_key(&mut self) -> Option<String> {
+        loop {
+            let key = self.dbs[self.db].random_key()?.clone();
+            // An expired key is removed on the way, so the loop ends
+            if self.live(&key) {
+                return Some(key);
+            }
+        }
+    }
+
+    /// Live keys matching the glob `pattern`
+    pub fn keys(&mut self, pattern: &str) -> Vec<String> {
+        let matched: Vec<String> = self.dbs[self.db]
+            .data
+            .keys()
+            .filter(|key| glob_match(pattern, key))
+            .cloned()
+            .collect();
+        matched.into_iter().filter(|key| self.live(key)).collect()
+    }
+
+    /// One step of SCAN: visit about `count` keys in hash order from `cursor`.
+    /// Keys sharing a hash are visited in the same step, so a key present during the whole
+    /// iteration is returned exactly once, however the keyspace changes meanwhile.
+    /// return: the cursor of the next step (0 when done), and the live keys visited
+    /// matching `pattern` and `type_name`
+    pub fn scan(
+        &mut self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&str>,
+        type_name: Option<&str>,
+    ) -> (u64, Vec<String>) {
+        let mut next = 0;
+        let mut visited: Vec<String> = Vec::new();
+        let mut last_hash = None;
+        let from = (cursor, String::new());
+        for (steps, (hash, key)) in self.dbs[self.db].scan_index.range(from..).enumerate() {
+            if steps >= count && last_hash != Some(*hash) {
+                next = *hash;
+                break;
+            }
+            last_hash = Some(*hash);
+            if pattern.is_none_or(|pattern| glob_match(pattern, key)) {
+                visited.push(key.clone());
             }
-        } else {
-            None
         }
+        let keys = visited
+            .into_iter()
+            .filter(|key| {
+                self.live(key)
+                    && type_name.is_none_or(|type_name| {
+                        self.dbs[self.db].data[key].value.type_name() == type_name
+                    })
+            })
+            .collect();
+        (next, keys)
     }
 
     // TODO: Option with From trait to avoid special judge for exp=0
@@ -87,23 +868,140 @@ impl Redis {
 
     /// `exp_at`: milliseconds, 0 means never
     pub fn set_at(&mut self, key: &str, value: &str, exp_at: u128) {
-        self.kvs.data.insert(
-            key.to_string(),
-            TimedValue {
-                value: value.to_string(),
-                expired_at: if exp_at == 0 { None } else { Some(exp_at) },
-            },
+        if exp_at != 0 {
+            self.watch_deadline(key, exp_at);
+        }
+        self.insert_entry(
+            key,
+            TimedValue::new(
+                Value::Str(value.to_string()),
+                if exp_at == 0 { None } else { Some(exp_at) },
+            ),
         );
     }
 
     pub fn del(&mut self, key: &str) -> bool {
-        if let Some(_) = self.kvs.data.remove(key) {
+        if let Some(_) = self.remove_entry(key) {
             true
         } else {
             false
         }
     }
 
+    /// Replace the string at `key` by `f(current)`, keeping its expiry
+    fn update_str(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Option<&str>) -> Result<String>,
+    ) -> Result<String> {
+        match self.entry(key) {
+            Some(TimedValue {
+                value: Value::Str(s),
+                ..
+            }) => {
+                *s = f(Some(s))?;
+                Ok(s.clone())
+            }
+            Some(_) => Err(anyhow!(WRONGTYPE)),
+            None => {
+                let value = f(None)?;
+                self.set_at(key, &value, 0);
+                Ok(value)
+            }
+        }
+    }
+
+    /// Add `delta` to the integer at `key`, a missing key counting as 0
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let value = self.update_str(key, |current| {
+            let current = match current {
+                Some(s) => s
+                    .parse::<i64>()
+                    .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?,
+                None => 0,
+            };
+            let value = current
+                .checked_add(delta)
+                .ok_or(anyhow!("ERR increment or decrement would overflow"))?;
+            Ok(value.to_string())
+        })?;
+        Ok(value.parse().unwrap())
+    }
+
+    /// Add `delta` to the float at `key`, a missing key counting as 0
+    pub fn incr_by_float(&mut self, key: &str, delta: f64) -> Result<String> {
+        self.update_str(key, |current| {
+            let current = match current {
+                Some(s) => s
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|f| f.is_finite())
+                    .ok_or(anyhow!("ERR value is not a valid float"))?,
+                None => 0.0,
+            };
+            let value = current + delta;
+            if !value.is_finite() {
+                return Err(anyhow!("ERR increment would produce NaN or Infinity"));
+            }
+            Ok(value.to_string())
+        })
+    }
+
+    /// return: length of the string after appending
+    pub fn append(&mut self, key: &str, value: &str) -> Result<usize> {
+        let value = self.update_str(key, |current| {
+            Ok(current.unwrap_or_default().to_string() + value)
+        })?;
+        Ok(value.len())
+    }
+
+    pub fn strlen(&mut self, key: &str) -> Result<usize> {
+        Ok(self.get(key)?.map_or(0, |s| s.len()))
+    }
+
+    /// Overwrite the string from byte `offset`, padding with zero bytes if it is too short.
+    /// return: length of the string after the write
+    pub fn setrange(&mut self, key: &str, offset: usize, value: &str) -> Result<usize> {
+        if value.is_empty() {
+            // Nothing written, and a missing key is not created
+            return self.strlen(key);
+        }
+        let value = self.update_str(key, |current| {
+            let mut bytes = current.unwrap_or_default().as_bytes().to_vec();
+            let end = offset + value.len();
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[offset..end].copy_from_slice(value.as_bytes());
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        })?;
+        Ok(value.len())
+    }
+
+    /// Delete the string at `key`, returning it
+    pub fn getdel(&mut self, key: &str) -> Result<Option<String>> {
+        let value = self.get(key)?;
+        if value.is_some() {
+            self.del(key);
+        }
+        Ok(value)
+    }
+
+    /// Set when a live key expires, None to make it persistent.
+    /// return: whether the key exists
+    pub fn set_expiry(&mut self, key: &str, expired_at: Option<Timestamp>) -> bool {
+        match self.entry(key) {
+            Some(entry) => {
+                entry.expired_at = expired_at;
+                if let Some(at) = expired_at {
+                    self.watch_deadline(key, at);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn add_subscriber(&mut self, channel_name: &str) -> RcvHandle {
         let (tx, rx): (Sender<String>, Receiver<String>) = channel();
         self.channels
@@ -139,18 +1037,687 @@ impl Redis {
         cnt
     }
 
-    /// Serialize the data stored
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        self.kvs.serialize(&mut Serializer::new(&mut buf)).unwrap();
-        buf
+    /// Record a write to be propagated
+    pub fn propagate(&mut self, record: Record) {
+        self.effects.push((self.db, record));
+    }
+
+    /// Drain the writes recorded since the last call, with the database of each
+    pub fn take_effects(&mut self) -> Vec<(usize, Record)> {
+        std::mem::take(&mut self.effects)
+    }
+
+    /// The value stored at `key`; if the key is absent, `init` is inserted first when given
+    fn value_mut(&mut self, key: &str, init: Option<Value>) -> Option<&mut Value> {
+        if self.entry(key).is_none() {
+            self.insert_entry(key, TimedValue::new(init?, None));
+        }
+        self.dbs[self.db].data.get_mut(key).map(|tv| &mut tv.value)
+    }
+
+    /// The list stored at `key`; with `create`, an empty list is inserted if the key is absent
+    fn list_mut(&mut self, key: &str, create: bool) -> Result<Option<&mut VecDeque<String>>> {
+        match self.value_mut(key, create.then(|| Value::List(VecDeque::new()))) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(anyhow!(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    /// The hash stored at `key`; with `create`, an empty hash is inserted if the key is absent
+    fn hash_mut(
+        &mut self,
+        key: &str,
+        create: bool,
+    ) -> Result<Option<&mut HashMap<String, String>>> {
+        match self.value_mut(key, create.then(|| Value::Hash(HashMap::new()))) {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(anyhow!(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    /// The set stored at `key`; with `create`, an empty set is inserted if the key is absent
+    fn set_mut(&mut self, key: &str, create: bool) -> Result<Option<&mut HashSet<String>>> {
+        match self.value_mut(key, create.then(|| Value::Set(HashSet::new()))) {
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(anyhow!(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    /// The sorted set stored at `key`; with `create`, an empty one is inserted if the key is absent
+    fn zset_mut(&mut self, key: &str, create: bool) -> Result<Option<&mut SortedSet>> {
+        match self.value_mut(key, create.then(|| Value::ZSet(SortedSet::default()))) {
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(anyhow!(WRONGTYPE)),
+            None => Ok(None),
+        }
+    }
+
+    /// The sorted set stored at `key`, for reads
+    pub fn zset(&mut self, key: &str) -> Result<Option<&SortedSet>> {
+        Ok(self.zset_mut(key, false)?.map(|zset| &*zset))
+    }
+
+    /// Empty containers are never kept around
+    fn remove_if_empty(&mut self, key: &str) {
+        let empty = match self.dbs[self.db].data.get(key).map(|tv| &tv.value) {
+            Some(Value::List(list)) => list.is_empty(),
+            Some(Value::Hash(hash)) => hash.is_empty(),
+            Some(Value::Set(set)) => set.is_empty(),
+            Some(Value::ZSet(zset)) => zset.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.remove_entry(key);
+        }
+    }
+
+    /// return: length of the list after the push
+    pub fn lpush(&mut self, key: &str, values: &[&str]) -> Result<usize> {
+        let list = self.list_mut(key, true)?.unwrap();
+        for value in values {
+            list.push_front(value.to_string());
+        }
+        Ok(list.len())
+    }
+
+    /// return: length of the list after the push
+    pub fn rpush(&mut self, key: &str, values: &[&str]) -> Result<usize> {
+        let list = self.list_mut(key, true)?.unwrap();
+        for value in values {
+            list.push_back(value.to_string());
+        }
+        Ok(list.len())
+    }
+
+    /// Pop at most `count` elements, from the head if `left`
+    pub fn pop(&mut self, key: &str, count: usize, left: bool) -> Result<Vec<String>> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Ok(vec![]);
+        };
+        let count = count.min(list.len());
+        let popped = if left {
+            list.drain(..count).collect()
+        } else {
+            list.drain(list.len() - count..).rev().collect()
+        };
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    pub fn llen(&mut self, key: &str) -> Result<usize> {
+        Ok(self.list_mut(key, false)?.map_or(0, |list| list.len()))
+    }
+
+    /// Both ends are inclusive, negative indexes count from the tail
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<String>> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Ok(vec![]);
+        };
+        Ok(match Self::range(start, stop, list.len()) {
+            Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    pub fn lindex(&mut self, key: &str, index: i64) -> Result<Option<String>> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Ok(None);
+        };
+        Ok(Self::index(index, list.len()).map(|i| list[i].clone()))
+    }
+
+    pub fn lset(&mut self, key: &str, index: i64, value: &str) -> Result<()> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Err(anyhow!("ERR no such key"));
+        };
+        let Some(i) = Self::index(index, list.len()) else {
+            return Err(anyhow!("ERR index out of range"));
+        };
+        list[i] = value.to_string();
+        Ok(())
+    }
+
+    /// Remove `count` occurrences of `value`: from the head if `count` > 0,
+    /// from the tail if `count` < 0, all of them if `count` == 0.
+    /// return: number of removed elements
+    pub fn lrem(&mut self, key: &str, count: i64, value: &str) -> Result<usize> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Ok(0);
+        };
+        let limit = if count == 0 {
+            usize::MAX
+        } else {
+            count.unsigned_abs() as usize
+        };
+        let mut removed = 0;
+        if count >= 0 {
+            list.retain(|elem| {
+                let hit = removed < limit && elem == value;
+                removed += hit as usize;
+                !hit
+            });
+        } else {
+            let mut kept: VecDeque<String> = VecDeque::with_capacity(list.len());
+            while let Some(elem) = list.pop_back() {
+                if removed < limit && elem == value {
+                    removed += 1;
+                } else {
+                    kept.push_front(elem);
+                }
+            }
+            *list = kept;
+        }
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    /// Keep only the elements in the inclusive range
+    pub fn ltrim(&mut self, key: &str, start: i64, stop: i64) -> Result<()> {
+        let Some(list) = self.list_mut(key, false)? else {
+            return Ok(());
+        };
+        match Self::range(start, stop, list.len()) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+            None => list.clear(),
+        }
+        self.remove_if_empty(key);
+        Ok(())
+    }
+
+    /// Set the fields of a hash, `pairs` of field and value.
+    /// return: number of fields newly added
+    pub fn hset(&mut self, key: &str, pairs: &[(&str, &str)]) -> Result<usize> {
+        let hash = self.hash_mut(key, true)?.unwrap();
+        let mut added = 0;
+        for (field, value) in pairs {
+            added += hash.insert(field.to_string(), value.to_string()).is_none() as usize;
+        }
+        Ok(added)
+    }
+
+    pub fn hget(&mut self, key: &str, field: &str) -> Result<Option<String>> {
+        Ok(self
+            .hash_mut(key, false)?
+            .and_then(|hash| hash.get(field).cloned()))
+    }
+
+    /// return: number of removed fields
+    pub fn hdel(&mut self, key: &str, fields: &[&str]) -> Result<usize> {
+        let Some(hash) = self.hash_mut(key, false)? else {
+            return Ok(0);
+        };
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(**field).is_some())
+            .count();
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn hlen(&mut self, key: &str) -> Result<usize> {
+        Ok(self.hash_mut(key, false)?.map_or(0, |hash| hash.len()))
+    }
+
+    /// All field-value pairs of a hash, empty if the key is absent
+    pub fn hgetall(&mut self, key: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.hash_mut(key, false)?.map_or(vec![], |hash| {
+            hash.iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect()
+        }))
     }
 
-    /// De-serialize the data, WITH CURRENT DATA CLEARED
-    pub fn deserialize(&mut self, data: Vec<u8>) {
-        self.kvs = rmp_serde::from_slice(&data).unwrap();
+    /// return: value of the field after the increment
+    pub fn hincrby(&mut self, key: &str, field: &str, delta: i64) -> Result<i64> {
+        let hash = self.hash_mut(key, true)?.unwrap();
+        let current = match hash.get(field) {
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| anyhow!("ERR hash value is not an integer"))?,
+            None => 0,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or(anyhow!("ERR increment or decrement would overflow"))?;
+        hash.insert(field.to_string(), value.to_string());
+        Ok(value)
+    }
+
+    /// return: number of members newly added
+    pub fn sadd(&mut self, key: &str, members: &[&str]) -> Result<usize> {
+        let set = self.set_mut(key, true)?.unwrap();
+        Ok(members
+            .iter()
+            .filter(|member| set.insert(member.to_string()))
+            .count())
+    }
+
+    /// return: number of removed members
+    pub fn srem(&mut self, key: &str, members: &[&str]) -> Result<usize> {
+        let Some(set) = self.set_mut(key, false)? else {
+            return Ok(0);
+        };
+        let removed = members.iter().filter(|member| set.remove(**member)).count();
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn sismember(&mut self, key: &str, member: &str) -> Result<bool> {
+        Ok(self
+            .set_mut(key, false)?
+            .is_some_and(|set| set.contains(member)))
+    }
+
+    pub fn scard(&mut self, key: &str) -> Result<usize> {
+        Ok(self.set_mut(key, false)?.map_or(0, |set| set.len()))
+    }
+
+    /// Members of a set, empty if the key is absent
+    pub fn smembers(&mut self, key: &str) -> Result<HashSet<String>> {
+        Ok(self.set_mut(key, false)?.cloned().unwrap_or_default())
+    }
+
+    /// Remove and return at most `count` random members
+    pub fn spop(&mut self, key: &str, count: usize) -> Result<Vec<String>> {
+        let Some(set) = self.set_mut(key, false)? else {
+            return Ok(vec![]);
+        };
+        let popped = set
+            .iter()
+            .cloned()
+            .choose_multiple(&mut rand::thread_rng(), count);
+        for member in popped.iter() {
+            set.remove(member);
+        }
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    /// Random members: distinct ones if `count` >= 0,
+    /// otherwise exactly -`count` of them, possibly repeated
+    pub fn srandmember(&mut self, key: &str, count: i64) -> Result<Vec<String>> {
+        let Some(set) = self.set_mut(key, false)? else {
+            return Ok(vec![]);
+        };
+        let mut rng = rand::thread_rng();
+        if count >= 0 {
+            return Ok(set
+                .iter()
+                .cloned()
+                .choose_multiple(&mut rng, count as usize));
+        }
+        let members: Vec<&String> = set.iter().collect();
+        Ok((0..count.unsigned_abs())
+            .map(|_| members[rng.gen_range(0..members.len())].clone())
+            .collect())
+    }
+
+    /// Intersection / union / difference of the sets at `keys`, absent keys being empty sets
+    pub fn set_algebra(&mut self, op: SetOp, keys: &[&str]) -> Result<HashSet<String>> {
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            sets.push(self.smembers(key)?);
+        }
+        let mut sets = sets.into_iter();
+        let first = sets.next().unwrap_or_default();
+        Ok(sets.fold(first, |acc, set| match op {
+            SetOp::Inter => acc.intersection(&set).cloned().collect(),
+            SetOp::Union => acc.union(&set).cloned().collect(),
+            SetOp::Diff => acc.difference(&set).cloned().collect(),
+        }))
+    }
+
+    /// Overwrite `key`, whatever it held, with `set`. An empty set deletes the key
+    /// return: size of the stored set
+    pub fn store_set(&mut self, key: &str, set: HashSet<String>) -> usize {
+        let len = set.len();
+        if set.is_empty() {
+            self.remove_entry(key);
+        } else {
+            self.insert_entry(key, TimedValue::new(Value::Set(set), None));
+        }
+        len
+    }
+
+    /// Add or update members of a sorted set, `pairs` of score and member.
+    /// return: numbers of added and updated members, and the score of the last member touched
+    /// (None if it was skipped because of the flags)
+    pub fn zadd(
+        &mut self,
+        key: &str,
+        flags: &ZAddFlags,
+        pairs: &[(f64, &str)],
+    ) -> Result<(usize, usize, Option<f64>)> {
+        let zset = self.zset_mut(key, true)?.unwrap();
+        let (mut added, mut updated, mut last) = (0, 0, None);
+        for (score, member) in pairs {
+            last = None;
+            let new = match zset.score(member) {
+                None if flags.xx => continue,
+                None => *score,
+                Some(_) if flags.nx => continue,
+                Some(old) => {
+                    let new = if flags.incr { old + score } else { *score };
+                    if new.is_nan() {
+                        self.remove_if_empty(key);
+                        return Err(anyhow!("ERR resulting score is not a number (NaN)"));
+                    }
+                    if (flags.gt && new <= old) || (flags.lt && new >= old) {
+                        continue;
+                    }
+                    if new == old {
+                        last = Some(new);
+                        continue;
+                    }
+                    updated += 1;
+                    new
+                }
+            };
+            added += zset.insert(member, new) as usize;
+            last = Some(new);
+        }
+        self.remove_if_empty(key);
+        Ok((added, updated, last))
+    }
+
+    /// return: number of removed members
+    pub fn zrem(&mut self, key: &str, members: &[&str]) -> Result<usize> {
+        let Some(zset) = self.zset_mut(key, false)? else {
+            return Ok(0);
+        };
+        let removed = members.iter().filter(|member| zset.remove(member)).count();
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    /// Remove the members with rank in `start..end`, or the `-end..-start` highest ones if `rev`
+    pub fn zremrange(
+        &mut self,
+        key: &str,
+        ranks: (usize, usize),
+        rev: bool,
+    ) -> Result<Vec<(String, f64)>> {
+        let Some(zset) = self.zset_mut(key, false)? else {
+            return Ok(vec![]);
+        };
+        let (start, end) = if rev {
+            (
+                zset.len().saturating_sub(ranks.1),
+                zset.len().saturating_sub(ranks.0),
+            )
+        } else {
+            ranks
+        };
+        let mut removed = zset.remove_by_rank(start, end);
+        if rev {
+            removed.reverse();
+        }
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    /// Resolve a possibly negative index
+    fn index(index: i64, len: usize) -> Option<usize> {
+        let index = if index < 0 { index + len as i64 } else { index };
+        if index < 0 || index >= len as i64 {
+            None
+        } else {
+            Some(index as usize)
+        }
+    }
+
+    /// Resolve an inclusive, possibly negative range, clamped to the bounds.
+    /// None if the range is empty
+    pub fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+        let len = len as i64;
+        let start = if start < 0 {
+            (start + len).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            stop + len
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            None
+        } else {
+            Some((start as usize, stop as usize))
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            dbs: self
+                .dbs
+                .iter()
+                .map(|kvs| StoredKV {
+                    data: kvs.data.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    /// Start copying the data as it is now, `copy_step` by `copy_step`, the data being written
+    /// in between as usual.
+    /// return: handle of the copy
+    pub fn start_copy(&mut self) -> usize {
+        let handle = self.next_copy;
+        self.next_copy += 1;
+        self.copies.insert(handle, SnapshotCopy::new(self.dbs.len()));
+        handle
+    }
+
+    /// Copy up to `keys` more keys for the copy `handle`.
+    /// return: the copy once complete, which is then forgotten
+    pub fn copy_step(&mut self, handle: usize, keys: usize) -> Option<Snapshot> {
+        if !self.copies.get_mut(&handle)?.step(&self.dbs, keys) {
+            return None;
+        }
+        let copy = self.copies.remove(&handle)?;
+        let dbs = copy
+            .dbs
+            .into_iter()
+            .map(|data| StoredKV {
+                data,
+                ..Default::default()
+            })
+            .collect();
+        Some(Snapshot { dbs })
+    }
+
+    /// Let the copies under way take the entry of `key` in the selected database, before a write
+    fn before_write(&mut self, key: &str) {
+        let tv = self.dbs[self.db].data.get(key);
+        for copy in self.copies.values_mut() {
+            copy.keep(self.db, key, tv);
+        }
+    }
+
+    /// Copy all that is left for the copies under way, before whole databases are replaced
+    fn finish_copies(&mut self) {
+        for copy in self.copies.values_mut() {
+            copy.step(&self.dbs, usize::MAX);
+        }
+    }
+
+    /// De-serialize the data, WITH CURRENT DATA CLEARED.
+    /// The data is left as it is if `data` can't be read
+    pub fn deserialize(&mut self, data: Vec<u8>) -> Result<()> {
+        let dbs = rmp_serde::from_slice(&data)
+            .map_err(|e| anyhow!("ERR invalid serialized data: {e}"))?;
+        self.replace_dbs(dbs);
+        Ok(())
+    }
+
+    /// Replace the data with the entries of each database, as loaded from a snapshot
+    pub fn restore(&mut self, databases: Vec<Vec<Entry>>) -> Result<()> {
+        if databases.len() > self.dbs.len() {
+            return Err(anyhow!(
+                "ERR {} databases to restore, only {} configured",
+                databases.len(),
+                self.dbs.len()
+            ));
+        }
+        let dbs = databases
+            .into_iter()
+            .map(|entries| StoredKV {
+                data: entries
+                    .into_iter()
+                    .map(|(key, value, expired_at)| (key, TimedValue::new(value, expired_at)))
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        self.replace_dbs(dbs);
+        Ok(())
+    }
+
+    fn replace_dbs(&mut self, dbs: Vec<StoredKV>) {
+        self.finish_copies();
+        let databases = self.dbs.len();
+        self.dbs = dbs;
+        while self.dbs.len() < databases {
+            self.dbs.push(StoredKV::default());
+        }
+        for kvs in self.dbs.iter_mut() {
+            kvs.reindex();
+            kvs.measure();
+        }
+        self.db = 0;
     }
 
     /// New node added to current cluster
     pub fn new_node(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The keys and values of each database, sorted
+    fn contents(databases: Vec<Vec<Entry>>) -> Vec<Vec<(String, String)>> {
+        databases
+            .into_iter()
+            .map(|entries| {
+                let mut entries: Vec<(String, String)> = entries
+                    .into_iter()
+                    .map(|(key, value, _)| match value {
+                        Value::Str(s) => (key, s),
+                        Value::List(list) => (key, format!("{list:?}")),
+                        other => (key, other.type_name().to_string()),
+                    })
+                    .collect();
+                entries.sort();
+                entries
+            })
+            .collect()
+    }
+
+    fn filled() -> Redis {
+        let mut redis = Redis::new(3);
+        for i in 0..100 {
+            redis.set_after(&format!("k{i}"), &i.to_string(), 0);
+        }
+        redis.lpush("list", &["a"]).unwrap();
+        redis.select(2).unwrap();
+        redis.set_after("other", "db2", 0);
+        redis.select(0).unwrap();
+        redis
+    }
+
+    /// Complete the copy `handle`, `keys` at a time
+    fn finish(redis: &mut Redis, handle: usize, keys: usize) -> Snapshot {
+        loop {
+            if let Some(snapshot) = redis.copy_step(handle, keys) {
+                return snapshot;
+            }
+        }
+    }
+
+    #[test]
+    fn copy_without_writes() {
+        let mut redis = filled();
+        let handle = redis.start_copy();
+        let copy = finish(&mut redis, handle, 7);
+        assert_eq!(
+            contents(copy.into_databases()),
+            contents(redis.snapshot().into_databases())
+        );
+        // Forgotten once complete
+        assert!(redis.copy_step(handle, 7).is_none());
+    }
+
+    #[test]
+    fn copy_keeps_the_data_as_it_started() {
+        let mut redis = filled();
+        let before = contents(redis.snapshot().into_databases());
+        let handle = redis.start_copy();
+        assert!(redis.copy_step(handle, 30).is_none());
+        // Keys the copy went past and keys it did not reach yet alike
+        for i in 0..100 {
+            match i % 4 {
+                0 => redis.set_after(&format!("k{i}"), "new", 0),
+                1 => {
+                    redis.del(&format!("k{i}"));
+                }
+                2 => {
+                    redis
+                        .rename(&format!("k{i}"), &format!("renamed{i}"), false)
+                        .unwrap();
+                }
+                _ => {}
+            }
+            redis.set_after(&format!("added{i}"), "new", 0);
+            // Reads in between
+            redis.get(&format!("k{i}")).unwrap();
+        }
+        redis.lpush("list", &["b"]).unwrap();
+        redis.select(2).unwrap();
+        redis.set_after("other", "new", 0);
+        redis.select(0).unwrap();
+        let copy = finish(&mut redis, handle, 9);
+        assert_eq!(contents(copy.into_databases()), before);
+        assert_eq!(redis.get("k0").unwrap().as_deref(), Some("new"));
+        assert_eq!(redis.get("k1").unwrap(), None);
+    }
+
+    #[test]
+    fn copy_completed_before_databases_are_replaced() {
+        let mut redis = filled();
+        let before = contents(redis.snapshot().into_databases());
+        let handle = redis.start_copy();
+        assert!(redis.copy_step(handle, 10).is_none());
+        redis.swapdb(0, 2).unwrap();
+        redis.flushall(false);
+        redis.set_after("k0", "after the flush", 0);
+        let copy = finish(&mut redis, handle, 10);
+        assert_eq!(contents(copy.into_databases()), before);
+    }
+
+    #[test]
+    fn copies_started_at_different_times() {
+        let mut redis = filled();
+        let first = redis.start_copy();
+        assert!(redis.copy_step(first, 50).is_none());
+        redis.set_after("k1", "between", 0);
+        let after_write = contents(redis.snapshot().into_databases());
+        let second = redis.start_copy();
+        redis.set_after("k1", "last", 0);
+        redis.del("k2");
+        let second = finish(&mut redis, second, 3);
+        let first = contents(finish(&mut redis, first, 3).into_databases());
+        assert_eq!(contents(second.into_databases()), after_write);
+        let k1 = first[0].iter().find(|(key, _)| key == "k1").unwrap();
+        assert_eq!(k1.1, "1");
+        assert!(first[0].iter().any(|(key, _)| key == "k2"));
+    }
+}
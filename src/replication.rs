@@ -0,0 +1,438 @@
+//! The stream of writes of a node, as replicas receive it.
+//! It is made of the same RESP records as the AOF, and a position in it is a replication id
+//! and a byte offset: two nodes at the same position hold the same data.
+//! Each replica is sent the stream by its own task, from a queue the writes never wait on:
+//! a replica whose queue outgrows the output limits is disconnected instead
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+/// The last bytes of the stream, for replicas which reconnect to catch up on
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// Offset of the first byte held
+    start: u64,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize, start: u64) -> Self {
+        Self {
+            buf: VecDeque::new(),
+            capacity,
+            start,
+        }
+    }
+
+    /// Offset right after the last byte held
+    pub fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    /// Append to the stream, forgetting the oldest bytes beyond the capacity
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        let excess = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// Whether the stream from `offset` on is still held
+    pub fn holds(&self, offset: u64) -> bool {
+        (self.start..=self.end()).contains(&offset)
+    }
+
+    /// The bytes from `offset` on, None once they are no longer held
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if !self.holds(offset) {
+            return None;
+        }
+        Some(self.buf.range((offset - self.start) as usize..).copied().collect())
+    }
+}
+
+/// Bytes queued for a replica beyond which it is disconnected, 0 for no limit
+#[derive(Clone, Copy)]
+pub struct OutputLimit {
+    pub hard: usize,
+    /// Disconnects once exceeded for `soft_seconds` in a row
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+/// The end of a replica's queue fed with the stream
+struct Link {
+    sender: mpsc::UnboundedSender<Arc<str>>,
+    queued: Arc<AtomicUsize>,
+    /// Since when more than the soft limit is queued
+    over_soft_since: Option<Instant>,
+}
+
+/// The stream not yet sent to a replica, taken by the task sending it
+pub struct ReplicaQueue {
+    receiver: mpsc::UnboundedReceiver<Arc<str>>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl ReplicaQueue {
+    /// All the stream queued, once there is some.
+    /// None once the replica is disconnected
+    pub async fn next(&mut self) -> Option<String> {
+        let mut stream = self.receiver.recv().await?.to_string();
+        while let Ok(more) = self.receiver.try_recv() {
+            stream += &more;
+        }
+        Some(stream)
+    }
+
+    /// `len` bytes were received by the replica
+    pub fn sent(&self, len: usize) {
+        self.queued.fetch_sub(len, Ordering::Relaxed);
+    }
+}
+
+/// Where a node is in its replication stream, and the replicas following it.
+/// A master makes the stream of its writes; a replica takes on the one of its master
+pub struct Replication {
+    /// Identifies the history of the data, kept by the replicas which follow it
+    pub id: String,
+    pub backlog: Backlog,
+    limit: OutputLimit,
+    links: HashMap<Uuid, Link>,
+}
+
+impl Replication {
+    pub fn new(id: String, offset: u64, backlog_size: usize, limit: OutputLimit) -> Self {
+        Self {
+            id,
+            backlog: Backlog::new(backlog_size, offset),
+            limit,
+            links: HashMap::new(),
+        }
+    }
+
+    /// Bytes of the stream so far
+    pub fn offset(&self) -> u64 {
+        self.backlog.end()
+    }
+
+    /// Append to the stream, and queue it for each replica
+    pub fn feed(&mut self, stream: &str) {
+        self.backlog.push(stream.as_bytes());
+        let stream: Arc<str> = stream.into();
+        let limit = self.limit;
+        self.links.retain(|replica, link| {
+            let queued = link.queued.fetch_add(stream.len(), Ordering::Relaxed) + stream.len();
+            let over_soft = limit.soft > 0 && queued > limit.soft;
+            let since = match (over_soft, link.over_soft_since) {
+                (false, _) => None,
+                (true, None) => Some(Instant::now()),
+                (true, since) => since,
+            };
+            link.over_soft_since = since;
+            let over_hard = limit.hard > 0 && queued > limit.hard;
+            let soft_expired =
+                since.is_some_and(|since| since.elapsed() >= Duration::from_secs(limit.soft_seconds));
+            if over_hard || soft_expired {
+                warn!("Replica {} disconnected: {} bytes queued over the output limit", replica, queued);
+                return false;
+            }
+            // Fails only once the task is gone
+            link.sender.send(stream.clone()).is_ok()
+        });
+    }
+
+    /// Queue the stream from now on for a replica
+    pub fn attach(&mut self, replica: Uuid) -> ReplicaQueue {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let link = Link {
+            sender,
+            queued: queued.clone(),
+            over_soft_since: None,
+        };
+        self.links.insert(replica, link);
+        ReplicaQueue { receiver, queued }
+    }
+
+    /// Stop queueing for a replica, which ends its queue
+    pub fn detach(&mut self, replica: &Uuid) {
+        self.links.remove(replica);
+    }
+
+    /// Follow another history from `offset` on, as after a full sync.
+    /// The replicas following the previous one are disconnected
+    pub fn reset(&mut self, id: String, offset: u64) {
+        self.id = id;
+        self.backlog = Backlog::new(self.backlog.capacity, offset);
+        self.links.clear();
+    }
+
+    /// Whether a replica at `offset` of the history `id` can be sent only what it misses
+    pub fn can_continue(&self, id: &str, offset: u64) -> bool {
+        id == self.id && self.backlog.holds(offset)
+    }
+}
+
+/// The data of a full sync being received from the master, chunk by chunk
+#[derive(Default)]
+pub struct Transfer {
+    id: String,
+    data: Vec<u8>,
+}
+
+impl Transfer {
+    /// Take the chunk at `offset` of the transfer `id`. A chunk at 0 starts a transfer over,
+    /// one received already is taken again, as when the master did not get the answer.
+    /// return: why the chunk can't be taken
+    pub fn receive(&mut self, id: &str, offset: u64, chunk: &[u8]) -> Result<(), String> {
+        if offset == 0 {
+            self.id = id.to_string();
+            self.data.clear();
+        }
+        if self.id.is_empty() {
+            return Err(format!("chunk of transfer {}, none under way", id));
+        }
+        if self.id != id {
+            return Err(format!("chunk of transfer {}, receiving {}", id, self.id));
+        }
+        if offset > self.data.len() as u64 {
+            return Err(format!("chunk at {}, {} bytes received", offset, self.data.len()));
+        }
+        self.data.truncate(offset as usize);
+        self.data.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    /// The data received, if it is the whole of it: `length` bytes of CRC-32 `checksum`.
+    /// The transfer is over either way
+    pub fn finish(&mut self, length: u64, checksum: u32) -> Result<Vec<u8>, String> {
+        let data = std::mem::take(self).data;
+        if data.len() as u64 != length {
+            return Err(format!("{} bytes received of {}", data.len(), length));
+        }
+        if crate::snapshot::crc32(&data) != checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit() -> OutputLimit {
+        OutputLimit {
+            hard: 0,
+            soft: 0,
+            soft_seconds: 0,
+        }
+    }
+
+    #[test]
+    fn backlog_holds_the_last_bytes() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.end(), 100);
+        assert_eq!(backlog.since(100), Some(vec![]));
+        backlog.push(b"abcde");
+        assert_eq!(backlog.end(), 105);
+        assert_eq!(backlog.since(102), Some(b"cde".to_vec()));
+        backlog.push(b"fghij");
+        // Past the capacity, the oldest bytes are forgotten
+        assert_eq!(backlog.end(), 110);
+        assert!(!backlog.holds(101));
+        assert!(backlog.holds(102));
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(102), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.since(110), Some(vec![]));
+        // Nothing beyond the end
+        assert!(!backlog.holds(111));
+        assert_eq!(backlog.since(111), None);
+    }
+
+    #[test]
+    fn backlog_push_larger_than_the_capacity() {
+        let mut backlog = Backlog::new(4, 0);
+        backlog.push(b"0123456789");
+        assert_eq!(backlog.end(), 10);
+        assert!(!backlog.holds(5));
+        assert_eq!(backlog.since(6), Some(b"6789".to_vec()));
+    }
+
+    #[test]
+    fn continue_only_the_same_history_within_the_backlog() {
+        let mut repl = Replication::new("a".to_string(), 0, 4, limit());
+        repl.feed("0123456");
+        assert_eq!(repl.offset(), 7);
+        assert!(repl.can_continue("a", 7));
+        assert!(repl.can_continue("a", 3));
+        assert!(!repl.can_continue("a", 2));
+        assert!(!repl.can_continue("b", 7));
+        repl.reset("b".to_string(), 50);
+        assert_eq!(repl.offset(), 50);
+        assert!(repl.can_continue("b", 50));
+        assert!(!repl.can_continue("a", 7));
+    }
+
+    fn limited(hard: usize, soft: usize, soft_seconds: u64) -> Replication {
+        let limit = OutputLimit {
+            hard,
+            soft,
+            soft_seconds,
+        };
+        Replication::new("a".to_string(), 0, 64, limit)
+    }
+
+    #[test]
+    fn feed_queues_for_each_replica() {
+        let mut repl = limited(0, 0, 0);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut first_queue = repl.attach(first);
+        repl.feed("ab");
+        let mut second_queue = repl.attach(second);
+        repl.feed("cd");
+        assert_eq!(first_queue.receiver.try_recv().unwrap().as_ref(), "ab");
+        assert_eq!(first_queue.receiver.try_recv().unwrap().as_ref(), "cd");
+        assert_eq!(second_queue.receiver.try_recv().unwrap().as_ref(), "cd");
+        assert_eq!(first_queue.queued.load(Ordering::Relaxed), 4);
+        first_queue.sent(4);
+        assert_eq!(first_queue.queued.load(Ordering::Relaxed), 0);
+        // A replica whose task is gone is dropped at the next feed
+        drop(second_queue);
+        repl.feed("e");
+        assert!(repl.links.contains_key(&first));
+        assert!(!repl.links.contains_key(&second));
+    }
+
+    #[test]
+    fn hard_limit_disconnects_at_once() {
+        let mut repl = limited(10, 0, 0);
+        let replica = Uuid::new_v4();
+        let mut queue = repl.attach(replica);
+        repl.feed("0123456789");
+        assert!(repl.links.contains_key(&replica));
+        repl.feed("a");
+        assert!(!repl.links.contains_key(&replica));
+        // Its queue ends once what was queued is taken
+        assert!(queue.receiver.try_recv().is_ok());
+        assert!(matches!(
+            queue.receiver.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn hard_limit_counts_only_what_is_not_sent() {
+        let mut repl = limited(10, 0, 0);
+        let replica = Uuid::new_v4();
+        let queue = repl.attach(replica);
+        for _ in 0..5 {
+            repl.feed("01234567");
+            queue.sent(8);
+        }
+        assert!(repl.links.contains_key(&replica));
+    }
+
+    #[test]
+    fn soft_limit_disconnects_once_exceeded_long_enough() {
+        let mut repl = limited(0, 4, 60);
+        let replica = Uuid::new_v4();
+        let queue = repl.attach(replica);
+        repl.feed("012345");
+        assert!(repl.links.contains_key(&replica));
+        assert!(repl.links[&replica].over_soft_since.is_some());
+        // Back under the limit, the time over it starts again
+        queue.sent(6);
+        repl.feed("0");
+        assert!(repl.links[&replica].over_soft_since.is_none());
+        repl.feed("12345");
+        let link = repl.links.get_mut(&replica).unwrap();
+        link.over_soft_since = Some(Instant::now() - Duration::from_secs(61));
+        repl.feed("6");
+        assert!(!repl.links.contains_key(&replica));
+    }
+
+    #[test]
+    fn soft_limit_without_delay() {
+        let mut repl = limited(0, 4, 0);
+        let replica = Uuid::new_v4();
+        let _queue = repl.attach(replica);
+        repl.feed("0123");
+        assert!(repl.links.contains_key(&replica));
+        repl.feed("4");
+        assert!(!repl.links.contains_key(&replica));
+    }
+
+    #[test]
+    fn transfer_in_chunks() {
+        let mut transfer = Transfer::default();
+        transfer.receive("t", 0, b"abc").unwrap();
+        transfer.receive("t", 3, b"def").unwrap();
+        let data = transfer
+            .finish(6, crate::snapshot::crc32(b"abcdef"))
+            .unwrap();
+        assert_eq!(data, b"abcdef");
+        // Over once finished
+        assert!(transfer.receive("t", 6, b"g").is_err());
+    }
+
+    #[test]
+    fn transfer_chunk_sent_again() {
+        let mut transfer = Transfer::default();
+        transfer.receive("t", 0, b"abc").unwrap();
+        transfer.receive("t", 3, b"def").unwrap();
+        // The answer to the last chunk was lost
+        transfer.receive("t", 3, b"def").unwrap();
+        assert_eq!(
+            transfer
+                .finish(6, crate::snapshot::crc32(b"abcdef"))
+                .unwrap(),
+            b"abcdef"
+        );
+    }
+
+    #[test]
+    fn transfer_restarts_at_0() {
+        let mut transfer = Transfer::default();
+        transfer.receive("t", 0, b"abc").unwrap();
+        transfer.receive("u", 0, b"xy").unwrap();
+        // Chunks of the transfer given up on are refused
+        assert!(transfer.receive("t", 3, b"def").is_err());
+        transfer.receive("u", 2, b"z").unwrap();
+        assert_eq!(
+            transfer.finish(3, crate::snapshot::crc32(b"xyz")).unwrap(),
+            b"xyz"
+        );
+    }
+
+    #[test]
+    fn transfer_refuses_gaps() {
+        let mut transfer = Transfer::default();
+        // None under way, as after a restart
+        assert!(transfer.receive("t", 3, b"def").is_err());
+        transfer.receive("t", 0, b"abc").unwrap();
+        assert!(transfer.receive("t", 4, b"ef").is_err());
+        // What was received is kept
+        transfer.receive("t", 3, b"d").unwrap();
+        assert_eq!(
+            transfer.finish(4, crate::snapshot::crc32(b"abcd")).unwrap(),
+            b"abcd"
+        );
+    }
+
+    #[test]
+    fn transfer_checked_at_the_end() {
+        let mut transfer = Transfer::default();
+        transfer.receive("t", 0, b"abc").unwrap();
+        assert!(transfer.finish(4, crate::snapshot::crc32(b"abc")).is_err());
+        transfer.receive("t", 0, b"abc").unwrap();
+        assert!(transfer.finish(3, crate::snapshot::crc32(b"abd")).is_err());
+        // Over either way
+        assert!(transfer.receive("t", 3, b"d").is_err());
+    }
+}
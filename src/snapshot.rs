@@ -0,0 +1,711 @@
+//! Point-in-time images of the data, written by SAVE / BGSAVE and the `--save` schedules.
+//!
+//! A snapshot file is the magic `VODISNAP` and a little-endian `u32` format version,
+//! then sections: a 4-byte tag, the `u64` length of the payload, the payload and its CRC-32.
+//! `META` holds the creation time, the replication ID / offset and the AOF position the image
+//! was taken at, each `DB` one database, and `END` closes the file. Payloads are MessagePack
+//! with named fields, so that fields can be added; sections of unknown tags are skipped.
+//! Files of the older versions are migrated when read.
+//!
+//! At boot the image is loaded if the AOF still starts with the bytes it was taken after,
+//! and only the AOF past that offset is replayed.
+
+use crate::aof::{self, AofPosition};
+use crate::redis::{Entry, Timestamp, Value};
+use crate::zset::SortedSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const MAGIC: &[u8; 8] = b"VODISNAP";
+/// Start of the header line of version 1 files
+const V1_MAGIC: &[u8; 8] = b"VODIS-SN";
+const VERSION: u32 = 2;
+
+const TAG_META: &[u8; 4] = b"META";
+const TAG_DB: &[u8; 4] = b"DB\0\0";
+const TAG_END: &[u8; 4] = b"END\0";
+
+/// Database indexes beyond this are corrupt, rather than a reason to allocate
+const MAX_DATABASES: usize = 1 << 16;
+
+/// A failed save is tried again by the schedules after this many seconds
+const SAVE_RETRY_DELAY: u64 = 5;
+
+/// Where and when a snapshot was taken
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Meta {
+    /// Unix time in seconds
+    pub created_at: u64,
+    /// ID of the node which took it
+    pub repl_id: String,
+    /// Bytes of records the node had made
+    pub repl_offset: u64,
+    /// Position of the AOF
+    pub aof_offset: u64,
+    pub aof_digest: u64,
+}
+
+impl Meta {
+    pub fn aof_position(&self) -> AofPosition {
+        AofPosition {
+            offset: self.aof_offset,
+            digest: self.aof_digest,
+        }
+    }
+}
+
+pub struct Snapshot {
+    pub meta: Meta,
+    /// Entries of each database
+    pub databases: Vec<Vec<Entry>>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    /// Not a snapshot file
+    BadMagic,
+    /// Written by a newer server
+    UnsupportedVersion(u32),
+    /// The file ends within the section starting at `offset`, or before its end
+    Truncated { offset: u64 },
+    /// The section starting at `offset` doesn't match its checksum
+    Checksum { offset: u64 },
+    /// The section starting at `offset` can't be decoded
+    Corrupt { offset: u64, reason: String },
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{e}"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {version}")
+            }
+            SnapshotError::Truncated { offset } => {
+                write!(f, "truncated section at offset {offset}")
+            }
+            SnapshotError::Checksum { offset } => {
+                write!(f, "checksum mismatch in section at offset {offset}")
+            }
+            SnapshotError::Corrupt { offset, reason } => {
+                write!(f, "bad section at offset {offset}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// An entry as saved, independent of how the data is kept in memory
+#[derive(Serialize, Deserialize)]
+struct EntryImage {
+    key: String,
+    value: ValueImage,
+    /// Unix time in milliseconds
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "items", rename_all = "lowercase")]
+enum ValueImage {
+    String(String),
+    List(Vec<String>),
+    Hash(Vec<(String, String)>),
+    Set(Vec<String>),
+    ZSet(Vec<(String, f64)>),
+}
+
+impl From<Value> for ValueImage {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Str(s) => ValueImage::String(s),
+            Value::List(list) => ValueImage::List(list.into()),
+            Value::Hash(hash) => ValueImage::Hash(hash.into_iter().collect()),
+            Value::Set(set) => ValueImage::Set(set.into_iter().collect()),
+            Value::ZSet(zset) => ValueImage::ZSet(zset.range_by_rank(0, zset.len())),
+        }
+    }
+}
+
+impl From<ValueImage> for Value {
+    fn from(image: ValueImage) -> Self {
+        match image {
+            ValueImage::String(s) => Value::Str(s),
+            ValueImage::List(list) => Value::List(list.into()),
+            ValueImage::Hash(hash) => Value::Hash(hash.into_iter().collect()),
+            ValueImage::Set(set) => Value::Set(set.into_iter().collect()),
+            ValueImage::ZSet(members) => Value::ZSet(members.into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DbImage {
+    index: usize,
+    entries: Vec<EntryImage>,
+}
+
+/// Write the entries of each database to `path`, through a temporary file so that
+/// a crash never leaves a partial snapshot
+pub fn write(path: &str, databases: Vec<Vec<Entry>>, meta: &Meta) -> std::io::Result<()> {
+    // Next to `path`, as a rename can't cross file systems
+    let temp = Path::new(path)
+        .with_file_name(format!("temp-{}.snap", std::process::id()))
+        .to_string_lossy()
+        .into_owned();
+    let result = (|| {
+        let mut file = BufWriter::new(File::create(&temp)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        write_section(&mut file, TAG_META, &encode(meta)?)?;
+        for (index, entries) in databases.into_iter().enumerate() {
+            let entries = entries
+                .into_iter()
+                .map(|(key, value, expired_at)| EntryImage {
+                    key,
+                    value: value.into(),
+                    expires_at: expired_at.map(|at| at as u64),
+                })
+                .collect();
+            write_section(&mut file, TAG_DB, &encode(&DbImage { index, entries })?)?;
+        }
+        write_section(&mut file, TAG_END, &[])?;
+        file.into_inner()?.sync_all()?;
+        aof::replace_file(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+fn encode(value: &impl Serialize) -> std::io::Result<Vec<u8>> {
+    rmp_serde::to_vec_named(value).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn write_section(file: &mut impl Write, tag: &[u8; 4], payload: &[u8]) -> std::io::Result<()> {
+    file.write_all(tag)?;
+    file.write_all(&(payload.len() as u64).to_le_bytes())?;
+    file.write_all(payload)?;
+    file.write_all(&crc32(payload).to_le_bytes())
+}
+
+/// Whether the file at `path` is a snapshot, of any version, rather than an AOF
+pub fn is_snapshot(path: &str) -> std::io::Result<bool> {
+    let mut magic = Vec::new();
+    File::open(path)?.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+    Ok(magic == MAGIC || magic == V1_MAGIC)
+}
+
+/// The snapshot at `path`, None if there is none
+pub fn read(path: &str) -> Result<Option<Snapshot>, SnapshotError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 8];
+    read_exact(&mut reader, &mut magic, 0)?;
+    if &magic == V1_MAGIC {
+        return v1::read(reader, magic).map(Some);
+    }
+    if &magic != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let mut version = [0; 4];
+    read_exact(&mut reader, &mut version, 8)?;
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let mut snapshot = Snapshot {
+        meta: Meta::default(),
+        databases: Vec::new(),
+    };
+    let mut offset = 12;
+    loop {
+        let start = offset;
+        let mut header = [0; 12];
+        read_exact(&mut reader, &mut header, start)?;
+        let (tag, len) = header.split_at(4);
+        let tag: &[u8; 4] = tag.try_into().unwrap();
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let mut payload = Vec::new();
+        if (&mut reader).take(len).read_to_end(&mut payload)? as u64 != len {
+            return Err(SnapshotError::Truncated { offset: start });
+        }
+        let mut crc = [0; 4];
+        read_exact(&mut reader, &mut crc, start)?;
+        if u32::from_le_bytes(crc) != crc32(&payload) {
+            return Err(SnapshotError::Checksum { offset: start });
+        }
+        offset += 16 + len;
+        let corrupt = |e: rmp_serde::decode::Error| SnapshotError::Corrupt {
+            offset: start,
+            reason: e.to_string(),
+        };
+        match tag {
+            TAG_META => {
+                snapshot.meta = rmp_serde::from_slice(&payload).map_err(corrupt)?;
+            }
+            TAG_DB => {
+                let db: DbImage = rmp_serde::from_slice(&payload).map_err(corrupt)?;
+                if db.index >= MAX_DATABASES {
+                    return Err(SnapshotError::Corrupt {
+                        offset: start,
+                        reason: format!("database index {} out of range", db.index),
+                    });
+                }
+                if snapshot.databases.len() <= db.index {
+                    snapshot.databases.resize_with(db.index + 1, Vec::new);
+                }
+                snapshot.databases[db.index] = db
+                    .entries
+                    .into_iter()
+                    .map(|entry| {
+                        let expired_at = entry.expires_at.map(Timestamp::from);
+                        (entry.key, entry.value.into(), expired_at)
+                    })
+                    .collect();
+            }
+            TAG_END => return Ok(Some(snapshot)),
+            // Added by a newer server, and not needed to load the data
+            _ => {}
+        }
+    }
+}
+
+/// Fill `buf`, the end of the file being a truncation of the section at `offset`
+fn read_exact(reader: &mut impl Read, buf: &mut [u8], offset: u64) -> Result<(), SnapshotError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => SnapshotError::Truncated { offset },
+        _ => SnapshotError::Io(e),
+    })
+}
+
+/// Version 1: a text header line `VODIS-SNAP 1 <aof offset> <aof digest> <len>`,
+/// then a MessagePack dump of the databases as they were kept in memory
+mod v1 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Db {
+        data: HashMap<String, Entry>,
+    }
+
+    #[derive(Deserialize)]
+    struct Entry {
+        value: Value,
+        expired_at: Option<Timestamp>,
+    }
+
+    #[derive(Deserialize)]
+    enum Value {
+        Str(String),
+        List(Vec<String>),
+        Hash(HashMap<String, String>),
+        Set(Vec<String>),
+        ZSet(Vec<(String, f64)>),
+    }
+
+    impl From<Value> for super::Value {
+        fn from(value: Value) -> Self {
+            match value {
+                Value::Str(s) => super::Value::Str(s),
+                Value::List(list) => super::Value::List(list.into()),
+                Value::Hash(hash) => super::Value::Hash(hash),
+                Value::Set(set) => super::Value::Set(set.into_iter().collect()),
+                Value::ZSet(members) => super::Value::ZSet(SortedSet::from(members)),
+            }
+        }
+    }
+
+    pub(super) fn read(mut reader: impl BufRead, magic: [u8; 8]) -> Result<Snapshot, SnapshotError> {
+        let mut header = magic.to_vec();
+        reader.read_until(b'\n', &mut header)?;
+        let header = String::from_utf8_lossy(&header);
+        let invalid = || SnapshotError::Corrupt {
+            offset: 0,
+            reason: "invalid header".to_string(),
+        };
+        let fields: Vec<&str> = header.trim_end().split(' ').collect();
+        let ["VODIS-SNAP", "1", offset, digest, len] = fields[..] else {
+            return Err(invalid());
+        };
+        let parse = |field: &str| field.parse::<u64>().map_err(|_| invalid());
+        let meta = Meta {
+            aof_offset: parse(offset)?,
+            aof_digest: parse(digest)?,
+            ..Default::default()
+        };
+        let data_offset = header.len() as u64;
+        // Read up to the length of the header, which is not trusted with an allocation
+        let len = parse(len)?;
+        let mut data = Vec::new();
+        if (&mut reader).take(len).read_to_end(&mut data)? as u64 != len {
+            return Err(SnapshotError::Truncated { offset: data_offset });
+        }
+        let dbs: Vec<Db> = rmp_serde::from_slice(&data).map_err(|e| SnapshotError::Corrupt {
+            offset: data_offset,
+            reason: e.to_string(),
+        })?;
+        let databases = dbs
+            .into_iter()
+            .map(|db| {
+                db.data
+                    .into_iter()
+                    .map(|(key, entry)| (key, entry.value.into(), entry.expired_at))
+                    .collect()
+            })
+            .collect();
+        Ok(Snapshot { meta, databases })
+    }
+}
+
+/// CRC-32 (IEEE) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !bytes
+        .iter()
+        .fold(!0, |crc, byte| TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Changes since the last save, to run the `--save` schedules
+#[derive(Default)]
+pub struct SaveStatus {
+    /// Records written since the last save
+    pub dirty: AtomicU64,
+    /// Unix time of the last successful save, or of the start, as replied by LASTSAVE
+    pub last_save: AtomicU64,
+    /// Unix time of the last failed save
+    pub last_failure: AtomicU64,
+    pub saving: AtomicBool,
+}
+
+impl SaveStatus {
+    pub fn new(now: u64) -> Self {
+        let status = Self::default();
+        status.last_save.store(now, Ordering::Relaxed);
+        status
+    }
+
+    /// Whether a `(seconds, changes)` schedule is due: at least `changes` records were written
+    /// and `seconds` passed since the last save
+    pub fn due(&self, schedules: &[(u64, u64)], now: u64) -> bool {
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(self.last_save.load(Ordering::Relaxed));
+        !self.saving.load(Ordering::Relaxed)
+            && now.saturating_sub(self.last_failure.load(Ordering::Relaxed)) >= SAVE_RETRY_DELAY
+            && schedules
+                .iter()
+                .any(|(seconds, changes)| dirty >= *changes && elapsed >= *seconds)
+    }
+
+    /// A save of the data as it was with `dirty` records written finished
+    pub fn saved(&self, result: &std::io::Result<()>, dirty: u64, now: u64) {
+        match result {
+            Ok(()) => {
+                self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+                self.last_save.store(now, Ordering::Relaxed);
+            }
+            Err(_) => self.last_failure.store(now, Ordering::Relaxed),
+        }
+        self.saving.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A file in a directory of its own, as the temporary files of concurrent writes would
+    /// have the same name in the same directory. Removed with the directory when dropped
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("vodis-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir.join("dump.snap").to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(Path::new(&self.0).parent().unwrap());
+        }
+    }
+
+    fn meta() -> Meta {
+        Meta {
+            created_at: 1700000000,
+            repl_id: "id".to_string(),
+            repl_offset: 42,
+            aof_offset: 7,
+            aof_digest: 9,
+        }
+    }
+
+    fn databases() -> Vec<Vec<Entry>> {
+        let list = Value::List(VecDeque::from(vec!["a".to_string(), "b".to_string()]));
+        let hash = Value::Hash(HashMap::from([("f".to_string(), "v".to_string())]));
+        let zset = Value::ZSet(SortedSet::from(vec![("m".to_string(), 1.5)]));
+        vec![
+            vec![
+                (
+                    "s".to_string(),
+                    Value::Str("x".to_string()),
+                    Some(1800000000000),
+                ),
+                ("l".to_string(), list, None),
+            ],
+            vec![],
+            vec![("h".to_string(), hash, None), ("z".to_string(), zset, None)],
+        ]
+    }
+
+    /// The entries of a database as text, in key order
+    fn describe(entries: &[Entry]) -> Vec<String> {
+        let mut described: Vec<String> = entries
+            .iter()
+            .map(|(key, value, expired_at)| {
+                let value = match value {
+                    Value::Str(s) => s.clone(),
+                    Value::List(list) => format!("{list:?}"),
+                    Value::Hash(hash) => format!("{hash:?}"),
+                    Value::Set(set) => format!("{set:?}"),
+                    Value::ZSet(zset) => format!("{:?}", zset.range_by_rank(0, zset.len())),
+                };
+                format!("{key} {value} {expired_at:?}")
+            })
+            .collect();
+        described.sort();
+        described
+    }
+
+    fn read_error(path: &str) -> SnapshotError {
+        match read(path) {
+            Ok(_) => panic!("{path} read"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = TempFile::new("round-trip");
+        write(&file.0, databases(), &meta()).unwrap();
+        assert!(is_snapshot(&file.0).unwrap());
+        let snapshot = read(&file.0).unwrap().unwrap();
+        assert_eq!(snapshot.meta.repl_id, "id");
+        assert_eq!(snapshot.meta.repl_offset, 42);
+        assert_eq!(
+            snapshot.meta.aof_position(),
+            AofPosition {
+                offset: 7,
+                digest: 9
+            }
+        );
+        assert_eq!(snapshot.databases.len(), 3);
+        for (read, written) in snapshot.databases.iter().zip(databases()) {
+            assert_eq!(describe(read), describe(&written));
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(read(&TempFile::new("missing").0).unwrap().is_none());
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let file = TempFile::new("checksum");
+        write(&file.0, databases(), &meta()).unwrap();
+        let mut bytes = std::fs::read(&file.0).unwrap();
+        // The last byte of the payload of the last DB section, before its CRC and the END section
+        let at = bytes.len() - 16 - 4 - 1;
+        bytes[at] ^= 0xff;
+        std::fs::write(&file.0, &bytes).unwrap();
+        assert!(matches!(
+            read_error(&file.0),
+            SnapshotError::Checksum { .. }
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let file = TempFile::new("truncated");
+        write(&file.0, databases(), &meta()).unwrap();
+        let bytes = std::fs::read(&file.0).unwrap();
+        for len in [4, 10, 20, bytes.len() / 2, bytes.len() - 1] {
+            std::fs::write(&file.0, &bytes[..len]).unwrap();
+            let error = read_error(&file.0);
+            assert!(
+                matches!(error, SnapshotError::Truncated { .. }),
+                "{len}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_magic_and_version() {
+        let file = TempFile::new("magic");
+        std::fs::write(&file.0, b"*1\r\n$4\r\nPING\r\n").unwrap();
+        assert!(!is_snapshot(&file.0).unwrap());
+        assert!(matches!(read_error(&file.0), SnapshotError::BadMagic));
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((VERSION + 1).to_le_bytes());
+        std::fs::write(&file.0, &bytes).unwrap();
+        assert!(
+            matches!(read_error(&file.0), SnapshotError::UnsupportedVersion(v) if v == VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped() {
+        let file = TempFile::new("unknown");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        write_section(&mut bytes, b"NEW\0", b"from a newer server").unwrap();
+        write_section(&mut bytes, TAG_META, &encode(&meta()).unwrap()).unwrap();
+        write_section(&mut bytes, TAG_END, &[]).unwrap();
+        std::fs::write(&file.0, &bytes).unwrap();
+        let snapshot = read(&file.0).unwrap().unwrap();
+        assert_eq!(snapshot.meta.repl_offset, 42);
+        assert!(snapshot.databases.is_empty());
+    }
+
+    #[test]
+    fn database_index_out_of_range() {
+        let file = TempFile::new("index");
+        for index in [MAX_DATABASES, usize::MAX] {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend(VERSION.to_le_bytes());
+            let db = DbImage {
+                index,
+                entries: vec![],
+            };
+            write_section(&mut bytes, TAG_DB, &encode(&db).unwrap()).unwrap();
+            write_section(&mut bytes, TAG_END, &[]).unwrap();
+            std::fs::write(&file.0, &bytes).unwrap();
+            assert!(
+                matches!(
+                    read_error(&file.0),
+                    SnapshotError::Corrupt { offset: 12, .. }
+                ),
+                "{index}"
+            );
+        }
+    }
+
+    #[derive(Serialize)]
+    struct V1Db {
+        data: HashMap<String, V1Entry>,
+    }
+
+    #[derive(Serialize)]
+    struct V1Entry {
+        value: Value,
+        expired_at: Option<Timestamp>,
+    }
+
+    fn v1_file(dbs: &[V1Db], len: Option<usize>) -> Vec<u8> {
+        let data = rmp_serde::to_vec(dbs).unwrap();
+        let len = len.unwrap_or(data.len());
+        let mut bytes = format!("VODIS-SNAP 1 7 9 {len}\n").into_bytes();
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let file = TempFile::new("v1");
+        let entry = |value: &str, expired_at| V1Entry {
+            value: Value::Str(value.to_string()),
+            expired_at,
+        };
+        let zset = V1Entry {
+            value: Value::ZSet(SortedSet::from(vec![("m".to_string(), 2.0)])),
+            expired_at: None,
+        };
+        let dbs = [
+            V1Db {
+                data: HashMap::from([("a".to_string(), entry("1", Some(5)))]),
+            },
+            V1Db {
+                data: HashMap::from([("b".to_string(), entry("2", None)), ("z".to_string(), zset)]),
+            },
+        ];
+        std::fs::write(&file.0, v1_file(&dbs, None)).unwrap();
+        assert!(is_snapshot(&file.0).unwrap());
+        let snapshot = read(&file.0).unwrap().unwrap();
+        assert_eq!(
+            snapshot.meta.aof_position(),
+            AofPosition {
+                offset: 7,
+                digest: 9
+            }
+        );
+        assert_eq!(describe(&snapshot.databases[0]), vec!["a 1 Some(5)"]);
+        assert_eq!(
+            describe(&snapshot.databases[1]),
+            vec!["b 2 None", "z [(\"m\", 2.0)] None"]
+        );
+    }
+
+    #[test]
+    fn version_1_length_beyond_the_file() {
+        let file = TempFile::new("v1-length");
+        std::fs::write(&file.0, v1_file(&[], Some(usize::MAX))).unwrap();
+        assert!(matches!(
+            read_error(&file.0),
+            SnapshotError::Truncated { .. }
+        ));
+        std::fs::write(&file.0, b"VODIS-SNAP 1 x\n").unwrap();
+        assert!(matches!(
+            read_error(&file.0),
+            SnapshotError::Corrupt { offset: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn write_leaves_only_the_snapshot() {
+        let file = TempFile::new("temp");
+        write(&file.0, databases(), &meta()).unwrap();
+        let dir = Path::new(&file.0).parent().unwrap();
+        let names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["dump.snap"]);
+    }
+}
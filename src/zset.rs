@@ -0,0 +1,382 @@
+//! Sorted set: a member-score map plus an ordered index on (score, member).
+//!
+//! The index is a treap whose nodes keep their subtree size, so ranks, range
+//! boundaries and the i-th element are all found in O(log n).
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+type Link = Option<Box<Node>>;
+
+#[derive(Debug, Clone)]
+struct Node {
+    score: f64,
+    member: String,
+    priority: u32,
+    size: usize,
+    left: Link,
+    right: Link,
+}
+
+fn size(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+fn cmp(score: f64, member: &str, node: &Node) -> Ordering {
+    score
+        .total_cmp(&node.score)
+        .then_with(|| member.cmp(&node.member))
+}
+
+impl Node {
+    fn update(&mut self) {
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+/// Join two treaps, every element of `a` being smaller than those of `b`
+fn merge(a: Link, b: Link) -> Link {
+    match (a, b) {
+        (None, b) => b,
+        (a, None) => a,
+        (Some(mut a), Some(mut b)) => {
+            if a.priority > b.priority {
+                a.right = merge(a.right.take(), Some(b));
+                a.update();
+                Some(a)
+            } else {
+                b.left = merge(Some(a), b.left.take());
+                b.update();
+                Some(b)
+            }
+        }
+    }
+}
+
+/// Split a treap into the prefix of nodes satisfying `before` and the rest.
+/// `before` must hold for a prefix of the order
+fn split(link: Link, before: &impl Fn(&Node) -> bool) -> (Link, Link) {
+    match link {
+        None => (None, None),
+        Some(mut node) => {
+            if before(&node) {
+                let (l, r) = split(node.right.take(), before);
+                node.right = l;
+                node.update();
+                (Some(node), r)
+            } else {
+                let (l, r) = split(node.left.take(), before);
+                node.left = r;
+                node.update();
+                (l, Some(node))
+            }
+        }
+    }
+}
+
+/// Number of leading nodes satisfying `before`, which must hold for a prefix of the order
+fn count_before(mut link: &Link, before: &impl Fn(&Node) -> bool) -> usize {
+    let mut count = 0;
+    while let Some(node) = link {
+        if before(node) {
+            count += size(&node.left) + 1;
+            link = &node.right;
+        } else {
+            link = &node.left;
+        }
+    }
+    count
+}
+
+/// Collect the elements of rank `start..end` (in-order), skipping subtrees out of range
+fn collect(link: &Link, offset: usize, start: usize, end: usize, out: &mut Vec<(String, f64)>) {
+    let Some(node) = link else {
+        return;
+    };
+    if offset >= end || offset + node.size <= start {
+        return;
+    }
+    let rank = offset + size(&node.left);
+    collect(&node.left, offset, start, end, out);
+    if start <= rank && rank < end {
+        out.push((node.member.clone(), node.score));
+    }
+    collect(&node.right, rank + 1, start, end, out);
+}
+
+/// Flags of ZADD
+#[derive(Debug, Default)]
+pub struct ZAddFlags {
+    /// Only add new members
+    pub nx: bool,
+    /// Only update existing members
+    pub xx: bool,
+    /// Only update when the new score is greater
+    pub gt: bool,
+    /// Only update when the new score is less
+    pub lt: bool,
+    /// Increment the score instead of setting it
+    pub incr: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, f64)>", into = "Vec<(String, f64)>")]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    index: Link,
+}
+
+impl From<Vec<(String, f64)>> for SortedSet {
+    fn from(pairs: Vec<(String, f64)>) -> Self {
+        let mut zset = SortedSet::default();
+        for (member, score) in pairs {
+            zset.insert(&member, score);
+        }
+        zset
+    }
+}
+
+impl From<SortedSet> for Vec<(String, f64)> {
+    fn from(zset: SortedSet) -> Self {
+        zset.scores.into_iter().collect()
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Insert or update a member.
+    /// return: whether the member is new
+    pub fn insert(&mut self, member: &str, score: f64) -> bool {
+        let is_new = match self.scores.insert(member.to_string(), score) {
+            Some(old) => {
+                self.unlink(member, old);
+                false
+            }
+            None => true,
+        };
+        let (l, r) = split(self.index.take(), &|node| cmp(score, member, node).is_gt());
+        let node = Box::new(Node {
+            score,
+            member: member.to_string(),
+            priority: rand::thread_rng().gen(),
+            size: 1,
+            left: None,
+            right: None,
+        });
+        self.index = merge(merge(l, Some(node)), r);
+        is_new
+    }
+
+    /// return: whether the member was there
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.unlink(member, score);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn unlink(&mut self, member: &str, score: f64) {
+        let (l, rest) = split(self.index.take(), &|node| cmp(score, member, node).is_gt());
+        let (_, r) = split(rest, &|node| cmp(score, member, node).is_ge());
+        self.index = merge(l, r);
+    }
+
+    /// 0-based rank in ascending order
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(count_before(&self.index, &|node| {
+            cmp(score, member, node).is_gt()
+        }))
+    }
+
+    /// Elements with rank in `start..end`, in ascending order
+    pub fn range_by_rank(&self, start: usize, end: usize) -> Vec<(String, f64)> {
+        let mut out = Vec::with_capacity(end.saturating_sub(start).min(self.len()));
+        collect(&self.index, 0, start, end, &mut out);
+        out
+    }
+
+    /// Ranks `start..end` of the elements with a score within the bounds
+    pub fn ranks_by_score(&self, min: Bound<f64>, max: Bound<f64>) -> (usize, usize) {
+        let start = match min {
+            Bound::Included(min) => count_before(&self.index, &|node| node.score < min),
+            Bound::Excluded(min) => count_before(&self.index, &|node| node.score <= min),
+            Bound::Unbounded => 0,
+        };
+        let end = match max {
+            Bound::Included(max) => count_before(&self.index, &|node| node.score <= max),
+            Bound::Excluded(max) => count_before(&self.index, &|node| node.score < max),
+            Bound::Unbounded => self.len(),
+        };
+        (start, end.max(start))
+    }
+
+    /// Ranks `start..end` of the elements with a member within the bounds.
+    /// Only meaningful when all the scores are the same
+    pub fn ranks_by_lex(&self, min: Bound<&str>, max: Bound<&str>) -> (usize, usize) {
+        let start = match min {
+            Bound::Included(min) => count_before(&self.index, &|node| node.member.as_str() < min),
+            Bound::Excluded(min) => count_before(&self.index, &|node| node.member.as_str() <= min),
+            Bound::Unbounded => 0,
+        };
+        let end = match max {
+            Bound::Included(max) => count_before(&self.index, &|node| node.member.as_str() <= max),
+            Bound::Excluded(max) => count_before(&self.index, &|node| node.member.as_str() < max),
+            Bound::Unbounded => self.len(),
+        };
+        (start, end.max(start))
+    }
+
+    /// Remove the elements with rank in `start..end`
+    pub fn remove_by_rank(&mut self, start: usize, end: usize) -> Vec<(String, f64)> {
+        let removed = self.range_by_rank(start, end);
+        for (member, _) in removed.iter() {
+            self.remove(member);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The elements of `zset` sorted the slow way
+    fn sorted(zset: &SortedSet) -> Vec<(String, f64)> {
+        let mut pairs: Vec<(String, f64)> = zset.scores.clone().into_iter().collect();
+        pairs.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        pairs
+    }
+
+    fn zset(pairs: &[(&str, f64)]) -> SortedSet {
+        let mut zset = SortedSet::default();
+        for (member, score) in pairs {
+            zset.insert(member, *score);
+        }
+        zset
+    }
+
+    #[test]
+    fn ranks_follow_score_then_member() {
+        let zset = zset(&[("c", 1.0), ("a", 2.0), ("b", 1.0), ("d", -1.0)]);
+        assert_eq!(zset.rank("d"), Some(0));
+        assert_eq!(zset.rank("b"), Some(1));
+        assert_eq!(zset.rank("c"), Some(2));
+        assert_eq!(zset.rank("a"), Some(3));
+        assert_eq!(zset.rank("e"), None);
+    }
+
+    #[test]
+    fn update_moves_the_member() {
+        let mut zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        assert!(!zset.insert("a", 4.0));
+        assert_eq!(zset.len(), 3);
+        assert_eq!(zset.rank("a"), Some(2));
+        assert_eq!(size(&zset.index), 3);
+        assert!(zset.remove("b"));
+        assert!(!zset.remove("b"));
+        assert_eq!(
+            zset.range_by_rank(0, 10),
+            vec![("c".to_string(), 3.0), ("a".to_string(), 4.0)]
+        );
+    }
+
+    #[test]
+    fn matches_a_sorted_vec() {
+        let mut rng = rand::thread_rng();
+        let mut zset = SortedSet::default();
+        for _ in 0..2000 {
+            let member = format!("m{}", rng.gen_range(0..200));
+            if rng.gen_bool(0.2) {
+                zset.remove(&member);
+            } else {
+                zset.insert(&member, rng.gen_range(0..20) as f64);
+            }
+        }
+        let expected = sorted(&zset);
+        assert_eq!(size(&zset.index), expected.len());
+        assert_eq!(zset.range_by_rank(0, zset.len()), expected);
+        for (rank, (member, _)) in expected.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(rank));
+        }
+        let (start, end) = (expected.len() / 3, expected.len() / 2);
+        assert_eq!(zset.range_by_rank(start, end), expected[start..end]);
+    }
+
+    #[test]
+    fn range_by_rank_out_of_bounds() {
+        let zset = zset(&[("a", 1.0), ("b", 2.0)]);
+        assert_eq!(zset.range_by_rank(1, 100), vec![("b".to_string(), 2.0)]);
+        assert!(zset.range_by_rank(2, 100).is_empty());
+        assert!(zset.range_by_rank(1, 1).is_empty());
+        assert!(SortedSet::default().range_by_rank(0, 10).is_empty());
+    }
+
+    #[test]
+    fn ranks_by_score_bounds() {
+        let zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)]);
+        use Bound::*;
+        assert_eq!(zset.ranks_by_score(Included(2.0), Included(2.0)), (1, 3));
+        assert_eq!(zset.ranks_by_score(Excluded(1.0), Excluded(3.0)), (1, 3));
+        assert_eq!(zset.ranks_by_score(Excluded(2.0), Unbounded), (3, 4));
+        assert_eq!(zset.ranks_by_score(Unbounded, Excluded(1.0)), (0, 0));
+        assert_eq!(
+            zset.ranks_by_score(Included(f64::NEG_INFINITY), Included(f64::INFINITY)),
+            (0, 4)
+        );
+        // An empty range, min above max
+        assert_eq!(zset.ranks_by_score(Included(3.0), Included(1.0)), (3, 3));
+    }
+
+    #[test]
+    fn ranks_by_lex_bounds() {
+        let zset = zset(&[("a", 0.0), ("b", 0.0), ("c", 0.0), ("d", 0.0)]);
+        use Bound::*;
+        assert_eq!(zset.ranks_by_lex(Included("b"), Included("c")), (1, 3));
+        assert_eq!(zset.ranks_by_lex(Excluded("b"), Unbounded), (2, 4));
+        assert_eq!(zset.ranks_by_lex(Unbounded, Excluded("b")), (0, 1));
+        assert_eq!(zset.ranks_by_lex(Included("bb"), Included("bc")), (2, 2));
+        assert_eq!(zset.ranks_by_lex(Included("d"), Included("a")), (3, 3));
+    }
+
+    #[test]
+    fn remove_by_rank_keeps_the_rest() {
+        let mut zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        let removed = zset.remove_by_rank(1, 3);
+        assert_eq!(
+            removed,
+            vec![("b".to_string(), 2.0), ("c".to_string(), 3.0)]
+        );
+        assert_eq!(zset.len(), 2);
+        assert_eq!(zset.rank("d"), Some(1));
+        assert_eq!(sorted(&zset), zset.range_by_rank(0, 2));
+    }
+
+    #[test]
+    fn converts_to_and_from_pairs() {
+        let zset = zset(&[("a", 1.5), ("b", -2.0)]);
+        let pairs: Vec<(String, f64)> = zset.into();
+        let zset = SortedSet::from(pairs);
+        assert_eq!(
+            zset.range_by_rank(0, 2),
+            vec![("b".to_string(), -2.0), ("a".to_string(), 1.5)]
+        );
+    }
+}